@@ -1,57 +1,551 @@
 use crate::config;
+use crate::context::ContextSnapshot;
+use crate::edit::{self, Occurrence, TextEdit};
 use crate::exec;
+use crate::files::{self, Style};
+use crate::migrate;
+use crate::patch;
+use crate::policy::{self, Policy};
+use crate::paths::PathGuard;
+use crate::report::{ActionReport, ApplyReport, Status};
+use crate::rust_edit::{self, RustItemKind};
+use crate::sandbox;
+use crate::schedule;
+use crate::stale;
+use crate::template::Param;
+use crate::transaction::Transaction;
+use crate::verify::{self, Verify};
 use anyhow::{Context, Result};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Plan {
+    /// Plan format version; older plans are upgraded on load, see `migrate`.
+    #[serde(default = "migrate::unversioned")]
+    pub version: u32,
     pub description: String,
     pub actions: Vec<Action>,
+    /// Variables substituted as `{{name}}` in the actions; see `template`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, Param>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-pub enum Action {
-    WriteFile { path: String, content: String, #[serde(default)] create_dirs: bool },
-    Run { cmd: String, args: Vec<String> },
+impl Default for Plan {
+    fn default() -> Self {
+        Plan { version: migrate::CURRENT_VERSION, description: String::new(), actions: Vec::new(), params: BTreeMap::new() }
+    }
+}
+
+impl Plan {
+    /// Fill in missing expected hashes from the snapshot the plan was made from, so that
+    /// `apply` can tell when a file changed in between.
+    pub fn stamp_expected_hashes(&mut self, ctx: &ContextSnapshot) {
+        let hash_of = |p: &str| {
+            let p = p.strip_prefix("./").unwrap_or(p);
+            ctx.files.iter().find(|f| f.path.strip_prefix("./").unwrap_or(&f.path) == p).map(|f| f.hash.clone())
+        };
+        for act in &mut self.actions {
+            match &mut act.kind {
+                ActionKind::WriteFile { path, expected_hash, .. }
+                | ActionKind::EditFile { path, expected_hash, .. }
+                | ActionKind::InsertText { path, expected_hash, .. }
+                | ActionKind::ReplaceRustItem { path, expected_hash, .. }
+                | ActionKind::DeleteFile { path, expected_hash }
+                | ActionKind::RenameFile { from: path, expected_hash, .. } => {
+                    if expected_hash.is_none() { *expected_hash = hash_of(path); }
+                }
+                ActionKind::ApplyPatch { patch, expected_hashes, .. } => {
+                    let Ok(parsed) = patch::parse(patch) else { continue };
+                    for old in parsed.files.iter().filter_map(|f| f.old_path.as_deref()) {
+                        if expected_hashes.contains_key(old) { continue; }
+                        if let Some(h) = hash_of(old) { expected_hashes.insert(old.to_string(), h); }
+                    }
+                }
+                ActionKind::CreateDir { .. } | ActionKind::Run { .. } => {}
+            }
+        }
+    }
+}
+
+/// One step of a plan: what to do, plus how it is scheduled relative to the other steps.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Action {
+    /// Name other actions refer to in `depends_on` and `on_failure`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Ids of the actions that must succeed before this one runs; otherwise it is skipped.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+    /// Ids of the actions to run only if this one fails, e.g. cleanup.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub on_failure: Vec<String>,
+    /// Keep going (and keep the changes) when this action fails; its dependents are skipped.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub continue_on_error: bool,
+    /// Line endings, BOM, final newline or executable bit to use instead of keeping those of
+    /// the file being written.
+    #[serde(default, skip_serializing_if = "Style::is_keep")]
+    pub style: Style,
+    #[serde(flatten)]
+    pub kind: ActionKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ActionKind {
+    /// Replace the whole content of a file, creating it if needed.
+    WriteFile {
+        path: String,
+        content: String,
+        #[serde(default)]
+        create_dirs: bool,
+        /// Hash of the file the plan was made against; see `stale`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expected_hash: Option<String>,
+    },
+    /// Apply exact search/replace blocks to an existing file.
+    EditFile {
+        path: String,
+        edits: Vec<TextEdit>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expected_hash: Option<String>,
+    },
+    /// Insert text on its own lines before or after an anchor in an existing file.
+    InsertText {
+        path: String,
+        text: String,
+        /// Anchor to insert before (the line where it starts); exclusive with `after`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        before: Option<String>,
+        /// Anchor to insert after (the line where it ends); exclusive with `before`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        after: Option<String>,
+        /// Treat the anchor as a regular expression rather than literal text.
+        #[serde(default)]
+        regex: bool,
+        /// Which match to use when the anchor matches more than once.
+        #[serde(default, skip_serializing_if = "Occurrence::is_only")]
+        occurrence: Occurrence,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expected_hash: Option<String>,
+    },
+    /// Replace a Rust item found by module path and name, or add one to an impl block.
+    ReplaceRustItem {
+        path: String,
+        /// `module::name`, `Type::method`, or with `add` the impl block: `Type` or `Trait for Type`.
+        item: String,
+        /// Disambiguates names shared by several items, such as a struct and its impl.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        kind: Option<RustItemKind>,
+        /// The new item. The old one's attributes and doc comments are kept unless it has its own.
+        code: String,
+        /// Add `code` to the impl block named by `item` instead of replacing an item.
+        #[serde(default)]
+        add: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expected_hash: Option<String>,
+    },
+    /// Apply a multi-file unified diff.
+    ApplyPatch {
+        patch: String,
+        #[serde(default = "default_fuzz")]
+        fuzz: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_offset: Option<usize>,
+        /// Expected hash per patched path.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        expected_hashes: BTreeMap<String, String>,
+    },
+    /// Delete a file.
+    DeleteFile {
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expected_hash: Option<String>,
+    },
+    /// Move a file or directory.
+    RenameFile {
+        from: String,
+        to: String,
+        #[serde(default)]
+        overwrite: bool,
+        /// Expected hash of `from`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expected_hash: Option<String>,
+    },
+    /// Create a directory and its parents.
+    CreateDir { path: String },
+    /// Run a program with arguments (no shell).
+    Run {
+        cmd: String,
+        args: Vec<String>,
+        /// Directory to run in, relative to the project root. The project root by default.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cwd: Option<String>,
+        /// Variables to set on top of jpc's environment.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        env: BTreeMap<String, String>,
+        /// Start from an empty environment, so the program only sees `env`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        clear_env: bool,
+        /// Stop the program after this many seconds instead of the configured default.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout_secs: Option<u64>,
+    },
+}
+
+impl From<ActionKind> for Action {
+    fn from(kind: ActionKind) -> Self {
+        Action {
+            id: None,
+            depends_on: Vec::new(),
+            on_failure: Vec::new(),
+            continue_on_error: false,
+            style: Style::default(),
+            kind,
+        }
+    }
 }
 
 impl Action {
+    /// `003 (run:cargo build)`, with the id when there is one: `003 'build' (run:cargo build)`.
+    pub fn label(&self, index: usize) -> String {
+        match &self.id {
+            Some(id) => format!("{:03} '{}' ({})", index + 1, id, self.short()),
+            None => format!("{:03} ({})", index + 1, self.short()),
+        }
+    }
+
     pub fn short(&self) -> String {
-        match self {
-            Action::WriteFile { path, .. } => format!("write_file:{}", path),
-            Action::Run { cmd, args } => format!("run:{} {}", cmd, args.join(" ")),
+        match &self.kind {
+            ActionKind::WriteFile { path, .. } => format!("write_file:{}", path),
+            ActionKind::EditFile { path, edits, .. } => format!("edit_file:{} ({} edits)", path, edits.len()),
+            ActionKind::InsertText { path, .. } => format!("insert_text:{}", path),
+            ActionKind::ReplaceRustItem { path, item, add, .. } => {
+                format!("replace_rust_item:{} {}{}", path, if *add { "+" } else { "" }, item)
+            }
+            ActionKind::ApplyPatch { patch, .. } => match patch::parse(patch) {
+                Ok(p) => format!(
+                    "apply_patch:{}",
+                    p.files.iter().map(|f| f.target()).collect::<Vec<_>>().join(",")
+                ),
+                Err(_) => "apply_patch:<invalid>".into(),
+            },
+            ActionKind::DeleteFile { path, .. } => format!("delete_file:{}", path),
+            ActionKind::RenameFile { from, to, .. } => format!("rename_file:{} -> {}", from, to),
+            ActionKind::CreateDir { path } => format!("create_dir:{}", path),
+            ActionKind::Run { cmd, args, .. } => format!("run:{} {}", cmd, args.join(" ")),
+        }
+    }
+
+    /// The `(path, expected hash)` pairs the action was planned against.
+    pub fn expected_hashes(&self) -> Vec<(String, String)> {
+        match &self.kind {
+            ActionKind::WriteFile { path, expected_hash, .. }
+            | ActionKind::EditFile { path, expected_hash, .. }
+            | ActionKind::InsertText { path, expected_hash, .. }
+            | ActionKind::ReplaceRustItem { path, expected_hash, .. }
+            | ActionKind::DeleteFile { path, expected_hash }
+            | ActionKind::RenameFile { from: path, expected_hash, .. } => {
+                expected_hash.iter().map(|h| (path.clone(), h.clone())).collect()
+            }
+            ActionKind::ApplyPatch { expected_hashes, .. } => {
+                expected_hashes.iter().map(|(p, h)| (p.clone(), h.clone())).collect()
+            }
+            ActionKind::CreateDir { .. } | ActionKind::Run { .. } => Vec::new(),
+        }
+    }
+
+    /// Every file or directory path the action reads or modifies, as written in the plan.
+    pub fn paths(&self) -> Vec<String> {
+        match &self.kind {
+            ActionKind::WriteFile { path, .. }
+            | ActionKind::EditFile { path, .. }
+            | ActionKind::InsertText { path, .. }
+            | ActionKind::ReplaceRustItem { path, .. }
+            | ActionKind::DeleteFile { path, .. }
+            | ActionKind::CreateDir { path } => vec![path.clone()],
+            ActionKind::ApplyPatch { patch, .. } => patch::parse(patch)
+                .map(|p| {
+                    p.files
+                        .iter()
+                        .flat_map(|f| [f.old_path.clone(), f.new_path.clone()])
+                        .flatten()
+                        .collect()
+                })
+                .unwrap_or_default(),
+            ActionKind::RenameFile { from, to, .. } => vec![from.clone(), to.clone()],
+            ActionKind::Run { .. } => Vec::new(),
         }
     }
 }
 
-pub fn apply_plan(plan: &Plan) -> Result<()> {
-    for act in &plan.actions {
-        match act {
-            Action::WriteFile { path, content, create_dirs } => {
-                let p = PathBuf::from(path);
-                if *create_dirs {
-                    if let Some(parent) = p.parent() { fs::create_dir_all(parent)?; }
+/// How a plan gets applied.
+#[derive(Debug, Clone)]
+pub struct ApplyOptions {
+    pub guard: PathGuard,
+    /// Three-way merge stale files instead of rejecting the plan.
+    pub merge_stale: bool,
+    /// Checks to run once every action applied; failing them reverts the plan.
+    pub verify: Option<Verify>,
+    /// Limit on `run` actions that do not set `timeout_secs`.
+    pub run_timeout: Option<Duration>,
+    pub policy: Policy,
+    /// Run commands in a sandbox where only the project root is writable.
+    pub sandbox: bool,
+}
+
+/// Apply the actions of `plan` as a single transaction, in dependency order, recording what
+/// happened to each of them in `report`.
+///
+/// An action runs only once all of its `depends_on` succeeded, and is skipped otherwise. When
+/// one fails, its `on_failure` handlers run; then, unless it has `continue_on_error`, the rest
+/// of the plan is skipped and all files touched so far are put back the way they were before
+/// returning the error. Pressing Ctrl-C while a command runs stops it and aborts the plan the
+/// same way, without running handlers and regardless of `continue_on_error`. With
+/// `opts.verify`, the project is then checked, and the whole plan rolled back as well if that
+/// fails.
+///
+/// Every path is checked against the project root, every command against `opts.policy`, and
+/// every expected hash against the file on disk, before the first action runs.
+pub fn apply_plan(plan: &Plan, opts: &ApplyOptions, report: &mut ApplyReport) -> Result<()> {
+    check_paths(plan, &opts.guard)?;
+    policy::enforce(plan, &opts.policy)?;
+    if opts.sandbox { sandbox::probe(&sandbox::Layout::new(&opts.guard))?; }
+    let order = schedule::order(&plan.actions)?;
+    let plan = &stale::resolve(plan, &opts.guard, opts.merge_stale)?;
+    let ids = schedule::ids(&plan.actions);
+    let handlers: HashSet<usize> = plan.actions.iter().flat_map(|a| &a.on_failure).map(|h| ids[h.as_str()]).collect();
+    let mut tx = Transaction::new(&config::backups_dir()?);
+    for i in order {
+        if report.actions[i].status != Status::Pending { continue; }
+        let act = &plan.actions[i];
+        if handlers.contains(&i) {
+            report.actions[i].status = Status::NotNeeded;
+            continue;
+        }
+        let unmet = act.depends_on.iter().find_map(|d| match report.actions[ids[d.as_str()]].status {
+            Status::Applied => None,
+            Status::Failed | Status::TimedOut => Some(format!("depends on '{}', which failed", d)),
+            _ => Some(format!("depends on '{}', which was skipped", d)),
+        });
+        if let Some(reason) = unmet {
+            report.actions[i].status = Status::Skipped;
+            report.actions[i].message = Some(reason);
+            continue;
+        }
+        let Err(err) = run(i, plan, &ids, opts, &mut tx, report) else { continue };
+        let interrupted = err.is::<exec::Interrupted>();
+        if act.continue_on_error && !interrupted { continue; }
+
+        for a in report.actions.iter_mut().filter(|a| a.status == Status::Pending) {
+            a.status = Status::Skipped;
+            a.message = Some("plan aborted".into());
+        }
+        let undone = tx.rollback();
+        report.rolled_back = true;
+        let what = if interrupted { "was interrupted" } else { "failed" };
+        eprintln!("Action {} {}, rolling back {} change(s):", act.label(i), what, undone.len());
+        for line in &undone {
+            eprintln!("  {}", line);
+        }
+        return Err(err.context(format!("action {} {}; plan rolled back", act.label(i), what)));
+    }
+    let changed = tx.changed_files();
+    if let Some(v) = &opts.verify && !changed.is_empty() && !verify::run(v, &changed, plan, opts, report) {
+        if v.keep_on_failure {
+            report.apply_id = Some(tx.commit(plan)?.id);
+            anyhow::bail!("verification failed; the changes were kept (`jpc undo` reverts them)");
+        }
+        let undone = tx.rollback();
+        report.rolled_back = true;
+        if let Some(r) = &mut report.verification { r.reverted = true; }
+        eprintln!("Verification failed, rolling back {} change(s):", undone.len());
+        for line in &undone {
+            eprintln!("  {}", line);
+        }
+        anyhow::bail!("verification failed; plan rolled back (use --keep-on-failure to keep the changes)");
+    }
+    report.apply_id = Some(tx.commit(plan)?.id);
+    Ok(())
+}
+
+/// Run action `i`, and its failure handlers if it fails. A failing handler is reported
+/// but does not change the outcome of the action it handles.
+fn run(
+    i: usize,
+    plan: &Plan,
+    ids: &HashMap<&str, usize>,
+    opts: &ApplyOptions,
+    tx: &mut Transaction,
+    report: &mut ApplyReport,
+) -> Result<()> {
+    let act = &plan.actions[i];
+    let started = Instant::now();
+    let mark = tx.mark();
+    let rep = &mut report.actions[i];
+    let res = apply_action(act, opts, tx, rep);
+    rep.duration_ms = started.elapsed().as_millis() as u64;
+    rep.backups = tx.backups_since(mark).iter().map(|b| b.display().to_string()).collect();
+    match &res {
+        Ok(()) => rep.status = Status::Applied,
+        Err(e) => {
+            rep.status = if e.is::<exec::TimedOut>() { Status::TimedOut } else { Status::Failed };
+            rep.message = Some(format!("{:#}", e));
+        }
+    }
+    if res.as_ref().is_err_and(|e| !e.is::<exec::Interrupted>()) {
+        for h in act.on_failure.iter().map(|h| ids[h.as_str()]) {
+            if report.actions[h].status == Status::Pending { let _ = run(h, plan, ids, opts, tx, report); }
+        }
+    }
+    res
+}
+
+/// Reject the plan if any action touches a path outside the project root or a protected one,
+/// or runs a command from such a directory.
+pub fn check_paths(plan: &Plan, guard: &PathGuard) -> Result<()> {
+    let mut problems = Vec::new();
+    for (i, act) in plan.actions.iter().enumerate() {
+        for p in act.paths() {
+            if let Err(e) = guard.resolve(&p) {
+                problems.push(format!("action {}: {:#}", act.label(i), e));
+            }
+        }
+        if let ActionKind::Run { cwd: Some(dir), .. } = &act.kind && let Err(e) = guard.resolve(dir) {
+            problems.push(format!("action {}: cwd: {:#}", act.label(i), e));
+        }
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("plan rejected, nothing was applied:\n  {}", problems.join("\n  "));
+    }
+    Ok(())
+}
+
+fn apply_action(act: &Action, opts: &ApplyOptions, tx: &mut Transaction, rep: &mut ActionReport) -> Result<()> {
+    let guard = &opts.guard;
+    match &act.kind {
+        ActionKind::WriteFile { path, content, create_dirs, .. } => {
+            let p = guard.resolve(path)?;
+            #[allow(clippy::collapsible_if)]
+            if *create_dirs {
+                if let Some(parent) = p.parent() { tx.create_dir_all(parent)?; }
+            }
+            let original = fs::read(&p).ok().and_then(|b| String::from_utf8(b).ok());
+            tx.record_file(&p)?;
+            rep.bytes_written += files::write_text(&p, original.as_deref(), content, &act.style)?;
+        }
+        ActionKind::EditFile { path, edits, .. } => {
+            let p = guard.resolve(path)?;
+            let original = fs::read_to_string(&p).with_context(|| format!("reading {}", p.display()))?;
+            let updated = edit::apply_edits(path, &files::decode(&original), edits)?;
+            tx.record_file(&p)?;
+            rep.bytes_written += files::write_text(&p, Some(&original), &updated, &act.style)?;
+        }
+        ActionKind::InsertText { path, text, before, after, regex, occurrence, .. } => {
+            let p = guard.resolve(path)?;
+            let original = fs::read_to_string(&p).with_context(|| format!("reading {}", p.display()))?;
+            let decoded = files::decode(&original);
+            let updated = edit::insert_text(path, &decoded, text, before.as_deref(), after.as_deref(), *regex, *occurrence)?;
+            tx.record_file(&p)?;
+            rep.bytes_written += files::write_text(&p, Some(&original), &updated, &act.style)?;
+        }
+        ActionKind::ReplaceRustItem { path, item, kind, code, add, .. } => {
+            let p = guard.resolve(path)?;
+            let original = fs::read_to_string(&p).with_context(|| format!("reading {}", p.display()))?;
+            let updated = rust_edit::replace_item(path, &files::decode(&original), item, *kind, code, *add)?;
+            tx.record_file(&p)?;
+            rep.bytes_written += files::write_text(&p, Some(&original), &updated, &act.style)?;
+        }
+        ActionKind::ApplyPatch { patch: text, fuzz, max_offset, .. } => {
+            let parsed = patch::parse(text).context("parsing unified diff")?;
+            // Compute every file first so a failing hunk leaves the tree untouched.
+            let mut results = Vec::new();
+            for fp in &parsed.files {
+                let old = fp.old_path.as_deref().map(|o| guard.resolve(o)).transpose()?;
+                let new = fp.new_path.as_deref().map(|n| guard.resolve(n)).transpose()?;
+                if let Some(n) = &new && old.as_ref() != Some(n) && n.exists() {
+                    let what = if old.is_some() { "renames a file onto" } else { "creates" };
+                    anyhow::bail!("the diff {} {}, which already exists", what, n.display());
+                }
+                let original = match &old {
+                    Some(o) => Some(fs::read_to_string(o).with_context(|| format!("reading {}", o.display()))?),
+                    None => None,
+                };
+                let decoded = original.as_deref().map(files::decode).unwrap_or_default();
+                let updated = match &new {
+                    Some(_) => Some(patch::apply_file(fp, &decoded, *fuzz, *max_offset)?),
+                    None => None,
+                };
+                results.push((old, new, original, updated));
+            }
+            for (old, new, original, updated) in results {
+                if let (Some(p), Some(text)) = (&new, updated) {
+                    tx.record_file(p)?;
+                    if let Some(parent) = p.parent() { tx.create_dir_all(parent)?; }
+                    rep.bytes_written += files::write_text(p, original.as_deref(), &text, &act.style)?;
                 }
-                if p.exists() {
-                    let backups = config::backups_dir()?;
-                    let ts = chrono::Utc::now().format("%Y%m%d%H%M%S");
-                    let fname = p
-                        .file_name()
-                        .and_then(|s| s.to_str())
-                        .unwrap_or("file");
-                    let bpath = backups.join(format!("{}-{}.bak", fname, ts));
-                    let data = fs::read(&p).with_context(|| format!("reading {}", p.display()))?;
-                    fs::write(&bpath, data)?;
+                // Deleted, or renamed away from.
+                if let Some(p) = old.filter(|o| new.as_ref() != Some(o)) {
+                    tx.record_file(&p)?;
+                    fs::remove_file(&p).with_context(|| format!("removing {}", p.display()))?;
                 }
-                fs::write(&p, content).with_context(|| format!("writing {}", p.display()))?;
             }
-            Action::Run { cmd, args } => {
-                exec::run_cmd(cmd, args)?;
+        }
+        ActionKind::DeleteFile { path, .. } => {
+            let p = guard.resolve(path)?;
+            if !p.is_file() {
+                anyhow::bail!("cannot delete {}: not an existing file", p.display());
+            }
+            tx.record_file(&p)?;
+            fs::remove_file(&p).with_context(|| format!("removing {}", p.display()))?;
+        }
+        ActionKind::RenameFile { from, to, overwrite, .. } => {
+            let src = guard.resolve(from)?;
+            let dst = guard.resolve(to)?;
+            if !src.exists() {
+                anyhow::bail!("cannot rename {}: it does not exist", src.display());
+            }
+            if dst.exists() && (!*overwrite || !dst.is_file()) {
+                anyhow::bail!("cannot rename {} to {}: destination exists", src.display(), dst.display());
             }
+            if let Some(parent) = dst.parent() { tx.create_dir_all(parent)?; }
+            if src.is_file() {
+                tx.record_file(&src)?;
+                tx.record_file(&dst)?;
+                fs::rename(&src, &dst).with_context(|| format!("renaming {} to {}", src.display(), dst.display()))?;
+            } else {
+                fs::rename(&src, &dst).with_context(|| format!("renaming {} to {}", src.display(), dst.display()))?;
+                tx.record_move(&src, &dst);
+            }
+        }
+        ActionKind::CreateDir { path } => {
+            tx.create_dir_all(&guard.resolve(path)?)?;
+        }
+        ActionKind::Run { cmd, args, cwd, env, clear_env, timeout_secs } => {
+            let dir = guard.resolve(cwd.as_deref().unwrap_or("."))?;
+            if !dir.is_dir() { anyhow::bail!("working directory {} does not exist", dir.display()); }
+            let run_opts = exec::RunOptions {
+                dir: Some(dir),
+                env: env.clone(),
+                clear_env: *clear_env,
+                timeout: timeout_secs.map(Duration::from_secs).or(opts.run_timeout),
+                sandbox: opts.sandbox.then(|| sandbox::Layout::new(guard)),
+                ..Default::default()
+            };
+            let out = exec::capture(cmd, args, &run_opts)?;
+            let checked = out.check(cmd);
+            rep.exit_code = out.status.code();
+            rep.stdout = Some(out.stdout);
+            rep.stderr = Some(out.stderr);
+            checked?;
         }
     }
     Ok(())
 }
+
+fn default_fuzz() -> usize { 2 }
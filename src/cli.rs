@@ -27,10 +27,13 @@ pub enum Commands {
     },
 
     /// Create an action plan for a query, using local LLM
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
     Plan {
+        #[command(subcommand)]
+        command: Option<PlanCommand>,
         /// The user query (what to change/build/test)
-        #[arg()]
-        query: String,
+        #[arg(required = true)]
+        query: Option<String>,
         /// Token/length limit for the plan
         #[arg(long)]
         max_tokens: Option<usize>,
@@ -38,12 +41,53 @@ pub enum Commands {
 
     /// Apply a JSON plan file of actions (writes, commands)
     Apply {
-        /// Path to the JSON plan file
+        /// Path to the plan file (JSON, YAML, TOML or Markdown)
         #[arg()]
         plan_file: String,
         /// Do not actually perform changes, just show
         #[arg(long)]
         dry_run: bool,
+        /// Value for a plan variable, as NAME=VALUE (repeatable)
+        #[arg(long = "var", value_name = "NAME=VALUE")]
+        vars: Vec<String>,
+        /// Review each action (accept, skip, edit, accept all, quit) before applying
+        #[arg(long, conflicts_with = "dry_run")]
+        interactive: bool,
+        /// Three-way merge files changed since the plan was made instead of refusing
+        #[arg(long)]
+        merge_stale: bool,
+        /// Allow plan paths outside the project root (absolute paths, `..`, symlinks)
+        #[arg(long)]
+        allow_outside_root: bool,
+        /// Write a JSON report of what happened to each action to this file
+        #[arg(long, value_name = "FILE", conflicts_with = "dry_run")]
+        report: Option<String>,
+        /// Skip the post-apply verification (rustfmt, then `cargo check` or `verify_command`)
+        #[arg(long)]
+        no_verify: bool,
+        /// Keep the changes when verification fails instead of rolling them back
+        #[arg(long, conflicts_with = "no_verify")]
+        keep_on_failure: bool,
+        /// Run commands in a sandbox: only the project is writable, no network, private /tmp (Linux)
+        #[arg(long)]
+        sandbox: bool,
+    },
+
+    /// Restore the working tree to its state before the most recent applies
+    Undo {
+        /// Number of applies to undo, most recent first
+        #[arg(long, default_value_t = 1)]
+        steps: usize,
+        /// Restore even if files were modified after the apply
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// List recorded applies, most recent first
+    History {
+        /// Max number of entries to show
+        #[arg(long)]
+        limit: Option<usize>,
     },
 
     /// Lightweight chat with remote model (context-aware)
@@ -60,3 +104,46 @@ pub enum Commands {
         what: String,
     },
 }
+
+#[derive(Subcommand, Debug)]
+pub enum PlanCommand {
+    /// Print the JSON Schema of plan files
+    Schema,
+
+    /// Check a plan file and report every problem with its location
+    Validate {
+        /// Path to the plan file
+        #[arg()]
+        file: String,
+    },
+
+    /// Rewrite a plan file in place in the current plan format version
+    Upgrade {
+        /// Path to the plan file
+        #[arg()]
+        file: String,
+    },
+
+    /// Translate a plan between JSON, YAML, TOML and Markdown
+    Convert {
+        /// Path to the plan file
+        #[arg()]
+        file: String,
+        /// Output format: json, yaml, toml or markdown (default: from the output extension)
+        #[arg(long, value_name = "FORMAT")]
+        to: Option<String>,
+        /// Write to this file instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<String>,
+    },
+
+    /// Combine plans into one, failing on conflicting changes to the same file
+    Merge {
+        /// Plan files, applied in this order
+        #[arg(required = true, num_args = 2..)]
+        files: Vec<String>,
+        /// Write to this file instead of stdout; its extension picks the format
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<String>,
+    },
+}
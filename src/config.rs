@@ -1,8 +1,10 @@
+use crate::policy::Policy;
 use anyhow::{Context, Result};
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Config {
@@ -10,6 +12,22 @@ pub struct Config {
     pub api_key: Option<String>,
     pub model: Option<String>,
     pub project_root: Option<String>,
+    /// Paths (relative to the project root, or absolute) that plans may never modify.
+    pub protected_paths: Option<Vec<String>>,
+    /// Check the project after each apply and revert it on failure. Defaults to on when the
+    /// project root has a `Cargo.toml`.
+    pub verify: Option<bool>,
+    /// Run rustfmt on the `.rs` files a plan changed before the verify command.
+    pub verify_rustfmt: Option<bool>,
+    /// Program and arguments of the verification, run from the project root.
+    pub verify_command: Option<Vec<String>>,
+    /// Seconds a `run` action or the verify command may take before it is stopped, unless
+    /// the action sets its own `timeout_secs`. 0 means no limit.
+    pub run_timeout_secs: Option<u64>,
+    /// Run the commands of `run` actions in a sandbox (Linux only).
+    pub sandbox: Option<bool>,
+    /// Programs and arguments `run` actions may use.
+    pub policy: Option<Policy>,
 }
 
 fn config_paths() -> Result<(PathBuf, PathBuf)> {
@@ -60,6 +78,13 @@ fn merge(mut a: Config, b: Config) -> Config {
     if b.api_key.is_some() { a.api_key = b.api_key; }
     if b.model.is_some() { a.model = b.model; }
     if b.project_root.is_some() { a.project_root = b.project_root; }
+    if b.protected_paths.is_some() { a.protected_paths = b.protected_paths; }
+    if b.verify.is_some() { a.verify = b.verify; }
+    if b.verify_rustfmt.is_some() { a.verify_rustfmt = b.verify_rustfmt; }
+    if b.verify_command.is_some() { a.verify_command = b.verify_command; }
+    if b.run_timeout_secs.is_some() { a.run_timeout_secs = b.run_timeout_secs; }
+    if b.sandbox.is_some() { a.sandbox = b.sandbox; }
+    if b.policy.is_some() { a.policy = b.policy; }
     a
 }
 
@@ -70,11 +95,37 @@ fn default_config() -> Result<Config> {
         api_key: None,
         model: Some("tiny-llama".into()),
         project_root: Some(cwd),
+        protected_paths: Some(default_protected_paths()),
+        verify: None,
+        verify_rustfmt: Some(true),
+        verify_command: Some(default_verify_command()),
+        run_timeout_secs: Some(30 * 60),
+        sandbox: Some(false),
+        policy: Some(Policy::default()),
     })
 }
 
+pub fn default_protected_paths() -> Vec<String> {
+    vec![".git".into(), ".jpc".into()]
+}
+
+/// The configured limit on commands, if any.
+pub fn run_timeout(cfg: &Config) -> Option<Duration> {
+    cfg.run_timeout_secs.filter(|s| *s > 0).map(Duration::from_secs)
+}
+
+pub fn default_verify_command() -> Vec<String> {
+    ["cargo", "check", "--quiet", "--message-format=short"].map(String::from).to_vec()
+}
+
 pub fn backups_dir() -> Result<PathBuf> {
     let d = Path::new(".jpc").join("backups");
     if !d.exists() { fs::create_dir_all(&d)?; }
     Ok(d)
 }
+
+pub fn journal_dir() -> Result<PathBuf> {
+    let d = Path::new(".jpc").join("journal");
+    if !d.exists() { fs::create_dir_all(&d)?; }
+    Ok(d)
+}
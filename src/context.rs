@@ -1,3 +1,4 @@
+use crate::journal;
 use anyhow::{Context as _, Result};
 use regex::Regex;
 use serde::Serialize;
@@ -22,6 +23,8 @@ pub struct GitInfo {
 pub struct FileSnippet {
     pub path: String,
     pub bytes: usize,
+    /// Hash of the whole file on disk, even if `content` was truncated.
+    pub hash: String,
     pub content: String,
 }
 
@@ -44,14 +47,15 @@ pub fn gather_context(max_files: Option<usize>, max_bytes: Option<usize>) -> Res
     let mut out: Vec<FileSnippet> = Vec::new();
     for path in files.into_iter().take(max_files) {
         if !path.exists() { continue; }
-        let data = fs::read_to_string(&path).unwrap_or_default();
-        let mut content = data;
+        let raw = fs::read(&path).unwrap_or_default();
+        let hash = journal::hash_bytes(&raw);
+        let mut content = String::from_utf8(raw).unwrap_or_default();
         // Trim long files
         if content.len() > 64_000 { content.truncate(64_000); }
         let bytes = content.as_bytes().len();
         if used_bytes + bytes > max_bytes { break; }
         used_bytes += bytes;
-        out.push(FileSnippet { path: path.to_string_lossy().to_string(), bytes, content });
+        out.push(FileSnippet { path: path.to_string_lossy().to_string(), bytes, hash, content });
     }
 
     Ok(ContextSnapshot { cwd, git, files: out })
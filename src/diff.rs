@@ -0,0 +1,67 @@
+use similar::{ChangeTag, TextDiff};
+use std::io::IsTerminal;
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// A rendered unified diff with its line counts.
+#[derive(Debug, Clone, Default)]
+pub struct FileDiff {
+    pub text: String,
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// Colors are used when stdout is a terminal and `NO_COLOR` is not set.
+pub fn use_color() -> bool {
+    std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal()
+}
+
+/// Unified diff between `old` and `new` with three lines of context, colored if `color`.
+pub fn unified(old: &str, new: &str, old_label: &str, new_label: &str, color: bool) -> FileDiff {
+    let diff = TextDiff::from_lines(old, new);
+    let mut added = 0;
+    let mut removed = 0;
+    for change in diff.iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Insert => added += 1,
+            ChangeTag::Delete => removed += 1,
+            ChangeTag::Equal => {}
+        }
+    }
+    let raw = diff.unified_diff().context_radius(3).header(old_label, new_label).to_string();
+    let text = if color { colorize(&raw) } else { raw };
+    FileDiff { text, added, removed }
+}
+
+fn colorize(diff: &str) -> String {
+    let mut out = String::with_capacity(diff.len());
+    for line in diff.split_inclusive('\n') {
+        let color = if line.starts_with("+++") || line.starts_with("---") {
+            BOLD
+        } else if line.starts_with('+') {
+            GREEN
+        } else if line.starts_with('-') {
+            RED
+        } else if line.starts_with("@@") {
+            CYAN
+        } else {
+            out.push_str(line);
+            continue;
+        };
+        let body = line.trim_end_matches('\n');
+        out.push_str(color);
+        out.push_str(body);
+        out.push_str(RESET);
+        if body.len() < line.len() { out.push('\n'); }
+    }
+    out
+}
+
+/// Wrap `text` in bold when `color` is set, for section headers.
+pub fn bold(text: &str, color: bool) -> String {
+    if color { format!("{}{}{}", BOLD, text, RESET) } else { text.to_string() }
+}
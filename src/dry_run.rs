@@ -0,0 +1,253 @@
+use crate::actions::{Action, ActionKind, Plan};
+use crate::diff;
+use crate::edit;
+use crate::exec;
+use crate::files;
+use crate::patch;
+use crate::rust_edit;
+use crate::schedule;
+use crate::stale;
+use crate::paths::PathGuard;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What one action would do, rendered for a reviewer.
+#[derive(Debug, Clone, Default)]
+pub struct ActionPreview {
+    pub text: String,
+    pub added: usize,
+    pub removed: usize,
+    pub failed: bool,
+}
+
+/// Replays actions against an in-memory view of the tree, so that each preview sees the
+/// files as left by the previous actions without anything being written.
+#[derive(Clone)]
+pub struct Simulator<'a> {
+    guard: &'a PathGuard,
+    color: bool,
+    /// Simulated content per path; `None` means the file would not exist.
+    files: HashMap<PathBuf, Option<Vec<u8>>>,
+}
+
+impl<'a> Simulator<'a> {
+    pub fn new(guard: &'a PathGuard, color: bool) -> Self {
+        Simulator { guard, color, files: HashMap::new() }
+    }
+
+    /// Preview `act` and, if it would succeed, record its effect for the next actions.
+    pub fn preview(&mut self, act: &Action) -> ActionPreview {
+        self.try_preview(act).unwrap_or_else(|e| ActionPreview {
+            text: format!("  would FAIL: {:#}\n", e),
+            failed: true,
+            ..Default::default()
+        })
+    }
+
+    fn read(&self, p: &Path) -> Option<Vec<u8>> {
+        match self.files.get(p) {
+            Some(v) => v.clone(),
+            None => fs::read(p).ok(),
+        }
+    }
+
+    fn read_text(&self, p: &Path) -> Result<String> {
+        let data = self.read(p).ok_or_else(|| anyhow::anyhow!("{} does not exist", p.display()))?;
+        String::from_utf8(data).with_context(|| format!("{} is not UTF-8 text", p.display()))
+    }
+
+    fn try_preview(&mut self, act: &Action) -> Result<ActionPreview> {
+        let mut staged: Vec<(PathBuf, Option<Vec<u8>>)> = Vec::new();
+        let mut out = ActionPreview::default();
+        match &act.kind {
+            ActionKind::WriteFile { path, content, .. } => {
+                let p = self.guard.resolve(path)?;
+                let original = self.read(&p).and_then(|b| String::from_utf8(b).ok());
+                staged.push((p, Some(files::render(original.as_deref(), content, &act.style).into_bytes())));
+            }
+            ActionKind::EditFile { path, edits, .. } => {
+                let p = self.guard.resolve(path)?;
+                let original = self.read_text(&p)?;
+                let updated = edit::apply_edits(path, &files::decode(&original), edits)?;
+                staged.push((p, Some(files::render(Some(&original), &updated, &act.style).into_bytes())));
+            }
+            ActionKind::InsertText { path, text, before, after, regex, occurrence, .. } => {
+                let p = self.guard.resolve(path)?;
+                let original = self.read_text(&p)?;
+                let decoded = files::decode(&original);
+                let updated = edit::insert_text(path, &decoded, text, before.as_deref(), after.as_deref(), *regex, *occurrence)?;
+                staged.push((p, Some(files::render(Some(&original), &updated, &act.style).into_bytes())));
+            }
+            ActionKind::ReplaceRustItem { path, item, kind, code, add, .. } => {
+                let p = self.guard.resolve(path)?;
+                let original = self.read_text(&p)?;
+                let updated = rust_edit::replace_item(path, &files::decode(&original), item, *kind, code, *add)?;
+                staged.push((p, Some(files::render(Some(&original), &updated, &act.style).into_bytes())));
+            }
+            ActionKind::ApplyPatch { patch: text, fuzz, max_offset, .. } => {
+                let parsed = patch::parse(text).context("parsing unified diff")?;
+                for fp in &parsed.files {
+                    let old = fp.old_path.as_deref().map(|o| self.guard.resolve(o)).transpose()?;
+                    let new = fp.new_path.as_deref().map(|n| self.guard.resolve(n)).transpose()?;
+                    if let Some(n) = &new && old.as_ref() != Some(n) && (self.read(n).is_some() || n.is_dir()) {
+                        let what = if old.is_some() { "renames a file onto" } else { "creates" };
+                        anyhow::bail!("the diff {} {}, which already exists", what, n.display());
+                    }
+                    let original = match &old {
+                        Some(o) => Some(self.read_text(o)?),
+                        None => None,
+                    };
+                    let decoded = original.as_deref().map(files::decode).unwrap_or_default();
+                    // Deleted, or renamed away from.
+                    let gone = old.filter(|o| new.as_ref() != Some(o));
+                    if let Some(n) = new {
+                        let text = patch::apply_file(fp, &decoded, *fuzz, *max_offset)?;
+                        staged.push((n, Some(files::render(original.as_deref(), &text, &act.style).into_bytes())));
+                    }
+                    if let Some(o) = gone { staged.push((o, None)); }
+                }
+            }
+            ActionKind::DeleteFile { path, .. } => {
+                let p = self.guard.resolve(path)?;
+                if self.read(&p).is_none() {
+                    anyhow::bail!("cannot delete {}: not an existing file", p.display());
+                }
+                staged.push((p, None));
+            }
+            ActionKind::RenameFile { from, to, overwrite, .. } => {
+                let src = self.guard.resolve(from)?;
+                let dst = self.guard.resolve(to)?;
+                // As apply does: only an existing file, and only with `overwrite`, may be replaced.
+                let dst_file = self.read(&dst).is_some();
+                if (dst_file && !*overwrite) || (!dst_file && dst.exists() && !self.files.contains_key(&dst)) {
+                    anyhow::bail!("cannot rename {} to {}: destination exists", src.display(), dst.display());
+                }
+                out.text.push_str(&format!("  rename {} -> {}\n", from, to));
+                if src.is_dir() {
+                    return Ok(out);
+                }
+                let data = self.read(&src).ok_or_else(|| anyhow::anyhow!("{} does not exist", src.display()))?;
+                self.files.insert(src, None);
+                self.files.insert(dst, Some(data));
+                return Ok(out);
+            }
+            ActionKind::CreateDir { path } => {
+                let p = self.guard.resolve(path)?;
+                let note = if p.is_dir() { "already exists" } else { "new directory" };
+                out.text.push_str(&format!("  mkdir {} ({})\n", path, note));
+                return Ok(out);
+            }
+            ActionKind::Run { cmd, args, cwd, env, clear_env, timeout_secs } => {
+                let resolved = exec::which(cmd)
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_else(|| "NOT FOUND in PATH".into());
+                let dir = self.guard.resolve(cwd.as_deref().unwrap_or("."))?;
+                let missing = if dir.is_dir() { "" } else { " (does not exist)" };
+                out.text.push_str(&format!("  $ {}\n", exec::display_command(cmd, args)));
+                out.text.push_str(&format!("    program: {}\n    cwd:     {}{}\n", resolved, dir.display(), missing));
+                if *clear_env { out.text.push_str("    env:     cleared\n"); }
+                for (k, v) in env {
+                    out.text.push_str(&format!("    env:     {}={}\n", k, exec::shell_quote(v)));
+                }
+                if let Some(s) = timeout_secs { out.text.push_str(&format!("    timeout: {}s\n", s)); }
+                return Ok(out);
+            }
+        }
+
+        for (p, after) in staged {
+            let before = self.read(&p);
+            let fd = self.file_diff(&p, before.as_deref(), after.as_deref());
+            out.text.push_str(&fd.text);
+            out.added += fd.added;
+            out.removed += fd.removed;
+            self.files.insert(p, after);
+        }
+        Ok(out)
+    }
+
+    fn file_diff(&self, p: &Path, before: Option<&[u8]>, after: Option<&[u8]>) -> diff::FileDiff {
+        let label = self.guard.relative(p);
+        let mut notes = Vec::new();
+        match (before, after) {
+            (None, Some(_)) => notes.push("new file".to_string()),
+            (Some(_), None) => notes.push("deleted file".to_string()),
+            _ => {}
+        }
+        if let Some(b) = before.filter(|b| is_binary(b)) {
+            let what = if after.is_some() { "overwrites binary file" } else { "binary file" };
+            notes.push(format!("{} ({} bytes)", what, b.len()));
+            return diff::FileDiff { text: format!("  {} ({})\n", label, notes.join(", ")), ..Default::default() };
+        }
+        let old = before.map(|b| String::from_utf8_lossy(b).to_string()).unwrap_or_default();
+        let new = after.map(|b| String::from_utf8_lossy(b).to_string()).unwrap_or_default();
+        let old_label = if before.is_some() { format!("a/{}", label) } else { "/dev/null".into() };
+        let new_label = if after.is_some() { format!("b/{}", label) } else { "/dev/null".into() };
+        let mut fd = diff::unified(&old, &new, &old_label, &new_label, self.color);
+        let header = if notes.is_empty() { format!("  {}\n", label) } else { format!("  {} ({})\n", label, notes.join(", ")) };
+        if fd.text.is_empty() {
+            fd.text = format!("  {} (unchanged)\n", label);
+        } else {
+            fd.text.insert_str(0, &header);
+        }
+        fd
+    }
+}
+
+fn is_binary(data: &[u8]) -> bool {
+    data.contains(&0) || std::str::from_utf8(data).is_err()
+}
+
+/// Print what applying `plan` would do: a diff per file change, the resolved command line
+/// per `run`, and a summary of lines added and removed.
+pub fn print_plan(plan: &Plan, guard: &PathGuard) {
+    let color = diff::use_color();
+    let mut sim = Simulator::new(guard, color);
+    let (mut added, mut removed, mut failed) = (0, 0, 0);
+    println!("Would apply {} actions:", plan.actions.len());
+    match stale::check(plan, guard) {
+        Ok(stale) => {
+            for s in stale {
+                println!("warning: {}", s.describe());
+            }
+        }
+        Err(e) => println!("warning: could not check for stale files: {:#}", e),
+    }
+    let order = match schedule::order(&plan.actions) {
+        Ok(order) => order,
+        Err(e) => {
+            println!("error: {:#}", e);
+            (0..plan.actions.len()).collect()
+        }
+    };
+    for i in order {
+        let act = &plan.actions[i];
+        let mut notes = Vec::new();
+        if !act.depends_on.is_empty() { notes.push(format!("after {}", act.depends_on.join(", "))); }
+        if act.continue_on_error { notes.push("continues on error".to_string()); }
+        let handles: Vec<String> = plan
+            .actions
+            .iter()
+            .enumerate()
+            .filter(|(_, o)| act.id.as_ref().is_some_and(|id| o.on_failure.contains(id)))
+            .map(|(j, o)| o.id.clone().unwrap_or_else(|| format!("{:03}", j + 1)))
+            .collect();
+        if !handles.is_empty() { notes.push(format!("only if {} fails", handles.join(" or "))); }
+        let title = if notes.is_empty() { act.label(i) } else { format!("{} [{}]", act.label(i), notes.join("; ")) };
+        println!("{}", diff::bold(&title, color));
+        // Failure handlers normally do not run: preview them without affecting the others.
+        let pv = if handles.is_empty() { sim.preview(act) } else { sim.clone().preview(act) };
+        print!("{}", pv.text);
+        added += pv.added;
+        removed += pv.removed;
+        if pv.failed { failed += 1; }
+    }
+    println!(
+        "Summary: {} actions, {} lines added, {} lines removed{}",
+        plan.actions.len(),
+        added,
+        removed,
+        if failed > 0 { format!(", {} would fail", failed) } else { String::new() }
+    );
+}
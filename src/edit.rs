@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use regex::RegexBuilder;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// One exact search/replace block of an `edit_file` action.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TextEdit {
+    pub old: String,
+    pub new: String,
+}
+
+/// Apply `edits` in order to `content`. Each `old` block must match exactly once in the
+/// text as left by the previous edits, otherwise the whole edit fails.
+pub fn apply_edits(path: &str, content: &str, edits: &[TextEdit]) -> Result<String> {
+    let mut out = content.to_string();
+    for (i, e) in edits.iter().enumerate() {
+        if e.old.is_empty() {
+            anyhow::bail!("{}: edit #{} has an empty 'old' block", path, i + 1);
+        }
+        let count = out.matches(e.old.as_str()).count();
+        match count {
+            0 => anyhow::bail!("{}: edit #{}: 'old' block not found:\n{}", path, i + 1, e.old),
+            1 => out = out.replacen(e.old.as_str(), &e.new, 1),
+            n => anyhow::bail!(
+                "{}: edit #{}: 'old' block matches {} times, add surrounding lines to make it unique:\n{}",
+                path, i + 1, n, e.old
+            ),
+        }
+    }
+    Ok(out)
+}
+
+/// Which match of an `insert_text` anchor to insert at.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Occurrence {
+    /// `"only"` (the anchor must match exactly once), `"first"` or `"last"`.
+    Named(Position),
+    /// The nth match, counting from 1.
+    Nth(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Position {
+    Only,
+    First,
+    Last,
+}
+
+impl Default for Occurrence {
+    fn default() -> Self { Occurrence::Named(Position::Only) }
+}
+
+impl Occurrence {
+    pub fn is_only(&self) -> bool { *self == Occurrence::default() }
+}
+
+/// Insert `text` on its own line(s) before the line where the anchor match starts, or
+/// after the line where it ends. Exactly one of `before` and `after` is the anchor, a
+/// literal string unless `regex` is set (`^` and `$` then match at line boundaries).
+pub fn insert_text(
+    path: &str,
+    content: &str,
+    text: &str,
+    before: Option<&str>,
+    after: Option<&str>,
+    regex: bool,
+    occurrence: Occurrence,
+) -> Result<String> {
+    let (pattern, after_anchor) = match (before, after) {
+        (Some(b), None) => (b, false),
+        (None, Some(a)) => (a, true),
+        _ => anyhow::bail!("{}: insert_text needs exactly one of 'before' and 'after'", path),
+    };
+    if pattern.is_empty() {
+        anyhow::bail!("{}: the anchor is empty", path);
+    }
+    let matches: Vec<(usize, usize)> = if regex {
+        let re = RegexBuilder::new(pattern)
+            .multi_line(true)
+            .build()
+            .with_context(|| format!("{}: invalid anchor regex '{}'", path, pattern))?;
+        re.find_iter(content).map(|m| (m.start(), m.end())).collect()
+    } else {
+        content.match_indices(pattern).map(|(s, m)| (s, s + m.len())).collect()
+    };
+
+    let line_of = |pos: usize| content[..pos].matches('\n').count() + 1;
+    let (start, end) = match (occurrence, matches.len()) {
+        (_, 0) => anyhow::bail!("{}: anchor '{}' not found", path, pattern),
+        (Occurrence::Named(Position::Only), n) if n > 1 => {
+            let lines: Vec<String> = matches.iter().take(10).map(|m| line_of(m.0).to_string()).collect();
+            anyhow::bail!(
+                "{}: anchor '{}' is ambiguous, it matches {} times (lines {}{}); pick one with \"occurrence\" (\"first\", \"last\" or a number) or use a more specific anchor",
+                path, pattern, n, lines.join(", "), if n > 10 { ", ..." } else { "" }
+            );
+        }
+        (Occurrence::Named(Position::Only | Position::First), _) => matches[0],
+        (Occurrence::Named(Position::Last), n) => matches[n - 1],
+        (Occurrence::Nth(0), _) => anyhow::bail!("{}: occurrence counts from 1", path),
+        (Occurrence::Nth(k), n) if k > n => {
+            anyhow::bail!("{}: occurrence {} requested but anchor '{}' only matches {} time(s)", path, k, pattern, n)
+        }
+        (Occurrence::Nth(k), _) => matches[k - 1],
+    };
+
+    let pos = if !after_anchor {
+        content[..start].rfind('\n').map_or(0, |i| i + 1)
+    } else if end > 0 && content.as_bytes()[end - 1] == b'\n' {
+        end
+    } else {
+        content[end..].find('\n').map_or(content.len(), |i| end + i + 1)
+    };
+    let mut out = String::with_capacity(content.len() + text.len() + 2);
+    out.push_str(&content[..pos]);
+    if pos > 0 && !out.ends_with('\n') { out.push('\n'); }
+    out.push_str(text);
+    if !text.ends_with('\n') { out.push('\n'); }
+    out.push_str(&content[pos..]);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(old: &str, new: &str) -> TextEdit {
+        TextEdit { old: old.into(), new: new.into() }
+    }
+
+    #[test]
+    fn applies_edits_in_order() {
+        let out = apply_edits("f", "let a = 1;\nlet b = 2;\n", &[edit("a = 1", "a = 10"), edit("a = 10;\nlet b", "a = 10;\nlet c")]).unwrap();
+        assert_eq!(out, "let a = 10;\nlet c = 2;\n");
+    }
+
+    #[test]
+    fn old_block_must_match_once() {
+        let text = "x();\nx();\ny();\n";
+        assert!(apply_edits("f", text, &[edit("z();", "")]).unwrap_err().to_string().contains("not found"));
+        assert!(apply_edits("f", text, &[edit("x();", "")]).unwrap_err().to_string().contains("matches 2 times"));
+        assert!(apply_edits("f", text, &[edit("", "w();")]).unwrap_err().to_string().contains("empty 'old' block"));
+        // A later edit sees the text left by the earlier ones.
+        let out = apply_edits("f", text, &[edit("x();\ny();", "y();"), edit("x();", "z();")]).unwrap();
+        assert_eq!(out, "z();\ny();\n");
+    }
+
+    const TEXT: &str = "use a;\nfn x() {}\nuse b;\nfn y() {}\n";
+
+    fn insert(before: Option<&str>, after: Option<&str>, regex: bool, occurrence: Occurrence) -> Result<String> {
+        insert_text("f", TEXT, "// new", before, after, regex, occurrence)
+    }
+
+    #[test]
+    fn inserts_on_its_own_line() {
+        let only = Occurrence::default();
+        assert_eq!(insert(Some("x()"), None, false, only).unwrap(), "use a;\n// new\nfn x() {}\nuse b;\nfn y() {}\n");
+        assert_eq!(insert(None, Some("fn x"), false, only).unwrap(), "use a;\nfn x() {}\n// new\nuse b;\nfn y() {}\n");
+        // An anchor ending with the newline inserts right after it; so does the last line without one.
+        assert_eq!(insert(None, Some("use a;\n"), false, only).unwrap(), "use a;\n// new\nfn x() {}\nuse b;\nfn y() {}\n");
+        assert_eq!(insert_text("f", "a", "b", None, Some("a"), false, only).unwrap(), "a\nb\n");
+    }
+
+    #[test]
+    fn picks_the_requested_occurrence() {
+        let err = insert(None, Some("use "), false, Occurrence::default()).unwrap_err().to_string();
+        assert!(err.contains("matches 2 times (lines 1, 3)"), "{}", err);
+        let first = insert(None, Some("use "), false, Occurrence::Named(Position::First)).unwrap();
+        assert_eq!(first, "use a;\n// new\nfn x() {}\nuse b;\nfn y() {}\n");
+        let last = insert(None, Some("use "), false, Occurrence::Named(Position::Last)).unwrap();
+        assert_eq!(last, insert(None, Some("use "), false, Occurrence::Nth(2)).unwrap());
+        assert_eq!(last, "use a;\nfn x() {}\nuse b;\n// new\nfn y() {}\n");
+        assert!(insert(None, Some("use "), false, Occurrence::Nth(3)).unwrap_err().to_string().contains("only matches 2"));
+        assert!(insert(None, Some("use "), false, Occurrence::Nth(0)).is_err());
+        assert!(insert(None, Some("nowhere"), false, Occurrence::default()).unwrap_err().to_string().contains("not found"));
+    }
+
+    #[test]
+    fn regex_anchors_match_at_line_boundaries() {
+        let out = insert(Some(r"^fn \w+\(\)"), None, true, Occurrence::Named(Position::Last)).unwrap();
+        assert_eq!(out, "use a;\nfn x() {}\nuse b;\n// new\nfn y() {}\n");
+        // Literal anchors are not patterns.
+        assert!(insert(Some("fn .()"), None, false, Occurrence::default()).is_err());
+        assert!(insert(Some("("), None, true, Occurrence::default()).unwrap_err().to_string().contains("invalid anchor regex"));
+    }
+}
@@ -1,31 +1,403 @@
+use crate::sandbox::{Layout, Sandbox};
 use anyhow::{Context, Result};
-use std::io::{self, Write};
-use std::process::{Command, Stdio};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-pub fn run_cmd(cmd: &str, args: &[String]) -> Result<()> {
+/// How to run a command besides its program and arguments.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Working directory; jpc's own when unset.
+    pub dir: Option<PathBuf>,
+    /// Variables set on top of the inherited environment.
+    pub env: BTreeMap<String, String>,
+    /// Inherit no environment at all, only `env`.
+    pub clear_env: bool,
+    /// Stop the command once it has run this long.
+    pub timeout: Option<Duration>,
+    /// Run in a `Sandbox` laid out this way.
+    pub sandbox: Option<Layout>,
+    /// Run as a filter: feed this to stdin, and keep the whole stdout without echoing it.
+    pub input: Option<Vec<u8>>,
+}
+
+/// A command stopped because it ran past its timeout.
+#[derive(Debug, thiserror::Error)]
+#[error("command '{cmd}' timed out after {}s and was terminated", .after.as_secs())]
+pub struct TimedOut {
+    pub cmd: String,
+    pub after: Duration,
+}
+
+/// A command stopped because the user pressed Ctrl-C.
+#[derive(Debug, thiserror::Error)]
+#[error("command '{cmd}' was interrupted")]
+pub struct Interrupted {
+    pub cmd: String,
+}
+
+/// Exit status and output of a finished command. Each output keeps the first and last
+/// bytes the command printed, up to `KEEP_HEAD` and `KEEP_TAIL`.
+#[derive(Debug, Clone)]
+pub struct CmdOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+    /// stdout and stderr interleaved in the order they were printed.
+    pub output: String,
+    /// The timeout the command was stopped at.
+    pub timed_out: Option<Duration>,
+    pub interrupted: bool,
+}
+
+impl CmdOutput {
+    /// Fail unless the command exited successfully. A command that was stopped fails with
+    /// `Interrupted` or `TimedOut`.
+    pub fn check(&self, cmd: &str) -> Result<()> {
+        if self.interrupted {
+            return Err(Interrupted { cmd: cmd.to_string() }.into());
+        }
+        if let Some(after) = self.timed_out {
+            return Err(TimedOut { cmd: cmd.to_string(), after }.into());
+        }
+        if !self.status.success() {
+            anyhow::bail!("command '{}' failed with status {:?}", cmd, self.status);
+        }
+        Ok(())
+    }
+}
+
+/// Bytes kept from the start of an output, then from its end, where errors show up.
+const KEEP_HEAD: usize = 16 * 1024;
+const KEEP_TAIL: usize = 48 * 1024;
+
+/// The start and the end of a stream too long to keep whole.
+#[derive(Default)]
+struct Bounded {
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    omitted: u64,
+    /// Keep everything instead.
+    whole: bool,
+}
+
+impl Bounded {
+    fn push(&mut self, data: &[u8]) {
+        if self.whole { return self.head.extend_from_slice(data); }
+        let (head, rest) = data.split_at(KEEP_HEAD.saturating_sub(self.head.len()).min(data.len()));
+        self.head.extend_from_slice(head);
+        self.tail.extend(rest);
+        let excess = self.tail.len().saturating_sub(KEEP_TAIL);
+        if excess > 0 {
+            self.tail.drain(..excess);
+            self.omitted += excess as u64;
+        }
+    }
+
+    fn into_string(self) -> String {
+        let mut bytes = self.head;
+        if self.omitted > 0 {
+            bytes.extend_from_slice(format!("\n[... {} bytes omitted ...]\n", self.omitted).as_bytes());
+        }
+        bytes.extend(self.tail);
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+/// How often a running command is checked for exit, timeout and Ctrl-C.
+const POLL: Duration = Duration::from_millis(20);
+/// Time a stopped command gets to exit after SIGTERM, before it is killed.
+const GRACE: Duration = Duration::from_secs(3);
+
+pub fn run_cmd(cmd: &str, args: &[String], opts: &RunOptions) -> Result<()> {
+    capture(cmd, args, opts)?.check(cmd)
+}
+
+/// Run a program, echoing its output as it comes, and return it whatever its exit status.
+///
+/// The program runs in a process group of its own, with stdin closed unless `opts.input` is
+/// set. When it outlives `opts.timeout` or the user presses Ctrl-C, the whole group,
+/// including whatever the program started, gets SIGTERM, then SIGKILL if it is still there
+/// after `GRACE`.
+pub fn capture(cmd: &str, args: &[String], opts: &RunOptions) -> Result<CmdOutput> {
     let mut command = Command::new(cmd);
     command.args(args);
+    if let Some(dir) = &opts.dir { command.current_dir(dir); }
+    if opts.clear_env { command.env_clear(); }
+    command.envs(&opts.env);
+    command.stdin(if opts.input.is_some() { Stdio::piped() } else { Stdio::null() });
     command.stdout(Stdio::piped());
     command.stderr(Stdio::piped());
-    let mut child = command.spawn().with_context(|| format!("spawning '{}': {:?}", cmd, args))?;
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    if let Some(layout) = &opts.sandbox {
+        let cwd = match &opts.dir {
+            Some(d) => d.clone(),
+            None => std::env::current_dir()?,
+        };
+        Sandbox::new(layout, &cwd)?.install(&mut command);
+    }
+
+    let sigint = SigintGuard::install();
+    let sandboxed = if opts.sandbox.is_some() { " in the sandbox" } else { "" };
+    let mut child = command.spawn().with_context(|| format!("spawning '{}'{}: {:?}", cmd, sandboxed, args))?;
+    if let (Some(input), Some(mut stdin)) = (opts.input.clone(), child.stdin.take()) {
+        // A filter that exits early closes its stdin; what it printed tells why.
+        thread::spawn(move || stdin.write_all(&input));
+    }
+    let combined = Arc::new(Mutex::new(Bounded::default()));
+    let filter = opts.input.is_some();
+    let out = pump(child.stdout.take().expect("stdout is piped"), (!filter).then_some(io::stdout), filter, combined.clone());
+    let err = pump(child.stderr.take().expect("stderr is piped"), Some(io::stderr), false, combined.clone());
+
+    // Wait for the output to close too: a program can exit and leave behind children that
+    // still hold it open.
+    let started = Instant::now();
+    let mut status = None;
+    let mut timed_out = None;
+    let mut interrupted = false;
+    loop {
+        if status.is_none() { status = child.try_wait().with_context(|| format!("waiting for '{}'", cmd))?; }
+        if status.is_some() && out.is_finished() && err.is_finished() { break; }
+        if sigint.caught() {
+            interrupted = true;
+        } else if let Some(t) = opts.timeout.filter(|t| started.elapsed() >= *t) {
+            timed_out = Some(t);
+        } else {
+            thread::sleep(POLL);
+            continue;
+        }
+        eprintln!("Stopping '{}' ({})", cmd, if interrupted { "interrupted" } else { "timed out" });
+        terminate(&mut child);
+        break;
+    }
+    let status = match status {
+        Some(s) => s,
+        None => child.wait().with_context(|| format!("waiting for '{}'", cmd))?,
+    };
+    drop(sigint);
+    let stdout = out.join().expect("stdout reader panicked").with_context(|| format!("reading output of '{}'", cmd))?;
+    let stderr = err.join().expect("stderr reader panicked").with_context(|| format!("reading output of '{}'", cmd))?;
+    let output = std::mem::take(&mut *combined.lock().expect("output lock poisoned"));
+    Ok(CmdOutput {
+        status,
+        stdout: stdout.into_string(),
+        stderr: stderr.into_string(),
+        output: output.into_string(),
+        timed_out,
+        interrupted,
+    })
+}
+
+/// Stop `child` and everything in its process group: SIGTERM, then SIGKILL after `GRACE`.
+#[cfg(unix)]
+fn terminate(child: &mut Child) {
+    let group = -(child.id() as libc::pid_t);
+    // SAFETY: kill has no memory safety requirements.
+    unsafe { libc::kill(group, libc::SIGTERM) };
+    let deadline = Instant::now() + GRACE;
+    while Instant::now() < deadline && !matches!(child.try_wait(), Ok(Some(_))) {
+        thread::sleep(POLL);
+    }
+    // Also reaches the children that ignored SIGTERM or outlived the program.
+    // SAFETY: as above.
+    unsafe { libc::kill(group, libc::SIGKILL) };
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// Set by the SIGINT handler while a `SigintGuard` is installed.
+static SIGINT_CAUGHT: AtomicBool = AtomicBool::new(false);
 
-    let output = child.wait_with_output()?;
-    io::stdout().write_all(&output.stdout)?;
-    io::stderr().write_all(&output.stderr)?;
-    if !output.status.success() {
-        anyhow::bail!("command '{}' failed with status {:?}", cmd, output.status);
+/// Catches Ctrl-C while a command runs, so that jpc outlives it and can stop the command and
+/// roll back instead of dying halfway. The previous handler is restored on drop.
+struct SigintGuard {
+    #[cfg(unix)]
+    previous: libc::sighandler_t,
+}
+
+impl SigintGuard {
+    #[cfg(unix)]
+    fn install() -> SigintGuard {
+        extern "C" fn on_sigint(_: libc::c_int) {
+            SIGINT_CAUGHT.store(true, Ordering::SeqCst);
+        }
+        SIGINT_CAUGHT.store(false, Ordering::SeqCst);
+        let handler = on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+        let previous = unsafe { libc::signal(libc::SIGINT, handler) };
+        SigintGuard { previous }
+    }
+
+    #[cfg(not(unix))]
+    fn install() -> SigintGuard {
+        SigintGuard {}
+    }
+
+    fn caught(&self) -> bool {
+        SIGINT_CAUGHT.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for SigintGuard {
+    fn drop(&mut self) {
+        // SAFETY: puts back the handler `install` replaced.
+        #[cfg(unix)]
+        unsafe { libc::signal(libc::SIGINT, self.previous) };
     }
-    Ok(())
+}
+
+/// Copy `src` to `sink` as it arrives, on its own thread, keeping it in `combined` as well
+/// as in the returned buffer, whole if asked. Chunks are echoed while holding `combined` so
+/// that the terminal and the transcript agree on the order of stdout and stderr.
+fn pump<R, W>(
+    mut src: R,
+    sink: Option<fn() -> W>,
+    whole: bool,
+    combined: Arc<Mutex<Bounded>>,
+) -> JoinHandle<io::Result<Bounded>>
+where
+    R: Read + Send + 'static,
+    W: Write + 'static,
+{
+    thread::spawn(move || {
+        let mut own = Bounded { whole, ..Default::default() };
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = match src.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let chunk = &buf[..n];
+            {
+                let mut all = combined.lock().expect("output lock poisoned");
+                all.push(chunk);
+                // A closed terminal must not fail the command.
+                if let Some(sink) = sink {
+                    let mut w = sink();
+                    let _ = w.write_all(chunk).and_then(|_| w.flush());
+                }
+            }
+            own.push(chunk);
+        }
+        Ok(own)
+    })
 }
 
 pub fn cargo_build(args: &[String]) -> Result<()> {
     let mut all: Vec<String> = vec!["build".into()];
     all.extend_from_slice(args);
-    run_cmd("cargo", &all)
+    run_cmd("cargo", &all, &RunOptions::default())
 }
 
 pub fn cargo_test(args: &[String]) -> Result<()> {
     let mut all: Vec<String> = vec!["test".into()];
     all.extend_from_slice(args);
-    run_cmd("cargo", &all)
+    run_cmd("cargo", &all, &RunOptions::default())
+}
+
+/// Full path of the program `cmd` would run: looked up in `PATH` unless it contains a slash.
+pub fn which(cmd: &str) -> Option<PathBuf> {
+    if cmd.contains('/') {
+        let p = PathBuf::from(cmd);
+        return p.is_file().then_some(p);
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).map(|dir| dir.join(cmd)).find(|p| p.is_file())
+}
+
+/// The command line as it could be pasted into a shell.
+pub fn display_command(cmd: &str, args: &[String]) -> String {
+    std::iter::once(cmd)
+        .chain(args.iter().map(|s| s.as_str()))
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn shell_quote(s: &str) -> String {
+    let plain = !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    if plain { s.to_string() } else { format!("'{}'", s.replace('\'', r"'\''")) }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str, timeout: Option<Duration>) -> CmdOutput {
+        let opts = RunOptions { timeout, ..Default::default() };
+        capture("sh", &["-c".into(), script.into()], &opts).unwrap()
+    }
+
+    /// Whether `pid` is still running, not counting a zombie left for its parent to reap.
+    fn running(pid: libc::pid_t) -> bool {
+        std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .is_ok_and(|stat| stat.rsplit(')').next().and_then(|s| s.split_whitespace().next()) != Some("Z"))
+    }
+
+    #[test]
+    fn captures_stdout_and_stderr() {
+        let out = sh("echo one; echo two >&2; echo three; exit 3", None);
+        assert_eq!(out.stdout, "one\nthree\n");
+        assert_eq!(out.stderr, "two\n");
+        for line in ["one", "two", "three"] {
+            assert!(out.output.contains(line));
+        }
+        assert_eq!(out.status.code(), Some(3));
+        assert!(out.check("sh").unwrap_err().to_string().contains("failed with status"));
+    }
+
+    #[test]
+    fn stops_commands_at_their_timeout() {
+        let started = Instant::now();
+        let out = sh("echo started; sleep 30", Some(Duration::from_millis(200)));
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert_eq!(out.timed_out, Some(Duration::from_millis(200)));
+        // What was printed before the timeout was kept.
+        assert_eq!(out.stdout, "started\n");
+        assert!(out.check("sh").unwrap_err().downcast_ref::<TimedOut>().is_some());
+    }
+
+    #[test]
+    fn kills_the_whole_process_group() {
+        // Both the shell and its child ignore SIGTERM, and are only stopped by SIGKILL.
+        let out = sh("trap '' TERM; sleep 30 & echo $!; wait", Some(Duration::from_millis(200)));
+        assert!(out.timed_out.is_some());
+        let pid: libc::pid_t = out.stdout.trim().parse().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while running(pid) && Instant::now() < deadline {
+            thread::sleep(POLL);
+        }
+        assert!(!running(pid), "the background sleep outlived the command");
+    }
+
+    #[test]
+    fn feeds_filters_and_keeps_their_whole_output() {
+        let input = "x".repeat(KEEP_HEAD + KEEP_TAIL + 10);
+        let opts = RunOptions { input: Some(input.clone().into_bytes()), ..Default::default() };
+        let out = capture("cat", &[], &opts).unwrap();
+        assert_eq!(out.stdout, input);
+    }
+
+    #[test]
+    fn keeps_the_start_and_end_of_long_output() {
+        let mut b = Bounded::default();
+        b.push(&vec![b'a'; KEEP_HEAD]);
+        b.push(&[b'b'; 100]);
+        b.push(&vec![b'c'; KEEP_TAIL]);
+        let s = b.into_string();
+        assert!(s.starts_with(&"a".repeat(KEEP_HEAD)));
+        assert!(s.contains("[... 100 bytes omitted ...]"));
+        assert!(s.ends_with(&"c".repeat(KEEP_TAIL)));
+    }
 }
@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, Permissions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+/// Overrides for the conventions a written file otherwise keeps from the file it replaces.
+/// New files are written as given unless set here.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Style {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_endings: Option<LineEnding>,
+    /// Start the file with a UTF-8 byte order mark.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bom: Option<bool>,
+    /// End the file with a line ending.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_newline: Option<bool>,
+    /// Set (or clear) the executable bits. Ignored outside Unix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub executable: Option<bool>,
+}
+
+impl Style {
+    pub fn is_keep(&self) -> bool {
+        *self == Style::default()
+    }
+}
+
+/// Conventions of some text: `line_ending` is `None` without line endings or with a mix of
+/// both, `final_newline` is `None` for empty text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Conventions {
+    line_ending: Option<LineEnding>,
+    bom: bool,
+    final_newline: Option<bool>,
+}
+
+fn detect(text: &str) -> Conventions {
+    let crlf = text.matches("\r\n").count();
+    let lf = text.matches('\n').count() - crlf;
+    let line_ending = match (crlf, lf) {
+        (0, 0) => None,
+        (0, _) => Some(LineEnding::Lf),
+        (_, 0) => Some(LineEnding::Crlf),
+        _ => None,
+    };
+    let body = text.strip_prefix('\u{feff}').unwrap_or(text);
+    Conventions {
+        line_ending,
+        bom: body.len() < text.len(),
+        final_newline: (!body.is_empty()).then(|| body.ends_with('\n')),
+    }
+}
+
+/// `text` without its BOM and with LF line endings, the form edits and patches apply to.
+/// Text mixing both line endings is left as it is.
+pub fn decode(text: &str) -> String {
+    let conv = detect(text);
+    let body = text.strip_prefix('\u{feff}').unwrap_or(text);
+    if conv.line_ending == Some(LineEnding::Crlf) { body.replace("\r\n", "\n") } else { body.to_string() }
+}
+
+/// What to write in place of `original` (`None` for a new file or one that is not text):
+/// `text` with the line endings, BOM and final newline of `original`, or of `text` itself for
+/// a new file, except where `style` says otherwise.
+pub fn render(original: Option<&str>, text: &str, style: &Style) -> String {
+    let own = detect(text);
+    let mut conv = original.map(detect).unwrap_or(own);
+    // An original without line endings says nothing about which ones to use.
+    if conv.line_ending.is_none() { conv.line_ending = own.line_ending; }
+    if conv.final_newline.is_none() { conv.final_newline = own.final_newline; }
+    if let Some(le) = style.line_endings { conv.line_ending = Some(le); }
+    if let Some(b) = style.bom { conv.bom = b; }
+    if let Some(n) = style.final_newline { conv.final_newline = Some(n); }
+
+    let mut body = text.strip_prefix('\u{feff}').unwrap_or(text).to_string();
+    match conv.final_newline {
+        Some(true) if !body.is_empty() && !body.ends_with('\n') => body.push('\n'),
+        Some(false) => body.truncate(body.trim_end_matches(['\r', '\n']).len()),
+        _ => {}
+    }
+    let body = match conv.line_ending {
+        Some(LineEnding::Crlf) => body.replace("\r\n", "\n").replace('\n', "\r\n"),
+        Some(LineEnding::Lf) => body.replace("\r\n", "\n"),
+        None => body,
+    };
+    if conv.bom { format!("\u{feff}{}", body) } else { body }
+}
+
+/// Write `text` in place of the file at `path`, whose current content is `original`, keeping
+/// its conventions unless `style` overrides them. Returns the number of bytes written.
+pub fn write_text(path: &Path, original: Option<&str>, text: &str, style: &Style) -> Result<u64> {
+    let data = render(original, text, style);
+    write_atomic(path, data.as_bytes(), None)?;
+    if let Some(exec) = style.executable { set_executable(path, exec)?; }
+    Ok(data.len() as u64)
+}
+
+/// Replace `path` with `data` without ever leaving it half written: the data goes to a
+/// temporary file next to it that is then renamed over it. The file keeps its permissions
+/// unless `perms` is given, and a symlink keeps pointing to the file it targets.
+pub fn write_atomic(path: &Path, data: &[u8], perms: Option<Permissions>) -> Result<()> {
+    let is_link = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink());
+    let target = if is_link { fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()) } else { path.to_path_buf() };
+    let perms = perms.or_else(|| fs::metadata(&target).ok().map(|m| m.permissions()));
+    let tmp = temp_path(&target);
+    let res = (|| -> std::io::Result<()> {
+        let mut f = fs::OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+        f.write_all(data)?;
+        if let Some(p) = perms { f.set_permissions(p)?; }
+        f.sync_all()?;
+        fs::rename(&tmp, &target)
+    })();
+    if res.is_err() { let _ = fs::remove_file(&tmp); }
+    res.with_context(|| format!("writing {}", path.display()))
+}
+
+fn temp_path(target: &Path) -> PathBuf {
+    let name = target.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    target.with_file_name(format!(".{}.jpc-{}.tmp", name, std::process::id()))
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path, exec: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut perms = fs::metadata(path).with_context(|| format!("reading {}", path.display()))?.permissions();
+    let mode = perms.mode();
+    // Executable for whoever can read the file.
+    perms.set_mode(if exec { mode | ((mode & 0o444) >> 2) } else { mode & !0o111 });
+    fs::set_permissions(path, perms).with_context(|| format!("setting permissions of {}", path.display()))
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path, _exec: bool) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_conventions_of_the_original() {
+        let keep = Style::default();
+        assert_eq!(render(Some("\u{feff}a\r\nb\r\n"), "x\ny\n", &keep), "\u{feff}x\r\ny\r\n");
+        assert_eq!(render(Some("a\nb"), "x\ny\n", &keep), "x\ny");
+        // An original without line endings or content takes them from the new text.
+        assert_eq!(render(Some("a"), "x\r\ny\r\n", &keep), "x\r\ny");
+        assert_eq!(render(Some(""), "x\n", &keep), "x\n");
+        // New files are written as given.
+        assert_eq!(render(None, "x\r\ny", &keep), "x\r\ny");
+    }
+
+    #[test]
+    fn style_overrides_the_original() {
+        let style = Style { line_endings: Some(LineEnding::Crlf), bom: Some(false), final_newline: Some(true), executable: None };
+        assert_eq!(render(Some("\u{feff}a\nb"), "x\ny", &style), "x\r\ny\r\n");
+        let style = Style { line_endings: Some(LineEnding::Lf), final_newline: Some(false), ..Style::default() };
+        assert_eq!(render(Some("a\r\n"), "x\r\ny\r\n\r\n", &style), "x\ny");
+    }
+
+    #[test]
+    fn decodes_to_lf_without_bom() {
+        assert_eq!(decode("\u{feff}a\r\nb\r\n"), "a\nb\n");
+        // Mixed line endings are left alone.
+        assert_eq!(decode("a\r\nb\n"), "a\r\nb\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_through_symlinks_and_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let (file, link) = (dir.path().join("script.sh"), dir.path().join("link.sh"));
+        fs::write(&file, "old\n").unwrap();
+        fs::set_permissions(&file, Permissions::from_mode(0o640)).unwrap();
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        write_text(&link, Some("old\n"), "new", &Style { executable: Some(true), ..Style::default() }).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new\n");
+        assert_eq!(fs::metadata(&file).unwrap().permissions().mode() & 0o777, 0o750);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::actions::Plan;
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::path::Path;
+
+/// Syntaxes a plan file can be written in. They all describe the same `Plan`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+    /// A Markdown document with the plan in one or more ```` ```jpc-plan ```` fenced blocks.
+    Markdown,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Result<Format> {
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "json" => Format::Json,
+            "yaml" | "yml" => Format::Yaml,
+            "toml" => Format::Toml,
+            "md" | "markdown" => Format::Markdown,
+            other => anyhow::bail!("unknown plan format '{}' (expected json, yaml, toml or markdown)", other),
+        })
+    }
+
+    pub fn from_path(path: &str) -> Option<Format> {
+        let ext = Path::new(path).extension()?.to_str()?;
+        Format::from_name(ext).ok()
+    }
+
+    /// Format of a plan file: from its extension, or else from its content.
+    pub fn detect(path: &str, text: &str) -> Format {
+        if let Some(f) = Format::from_path(path) { return f; }
+        sniff(text)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Json => "JSON",
+            Format::Yaml => "YAML",
+            Format::Toml => "TOML",
+            Format::Markdown => "Markdown",
+        }
+    }
+}
+
+fn sniff(text: &str) -> Format {
+    let t = text.trim_start();
+    if t.starts_with('{') {
+        Format::Json
+    } else if !fences(text).is_empty() {
+        Format::Markdown
+    } else if toml::from_str::<toml::Table>(text).is_ok() {
+        Format::Toml
+    } else {
+        Format::Yaml
+    }
+}
+
+/// Parse a plan document into the JSON value it stands for.
+pub fn to_value(text: &str, format: Format) -> Result<Value> {
+    match format {
+        Format::Json => Ok(serde_json::from_str(text)?),
+        Format::Yaml => Ok(serde_yaml::from_str(text)?),
+        Format::Toml => Ok(toml::from_str(text)?),
+        Format::Markdown => markdown_to_value(text),
+    }
+}
+
+/// Write `plan` in `format`. Multi-line strings become YAML block scalars and TOML
+/// multi-line strings.
+pub fn render(plan: &Plan, format: Format) -> Result<String> {
+    Ok(match format {
+        Format::Json => serde_json::to_string_pretty(plan)? + "\n",
+        Format::Yaml => serde_yaml::to_string(plan)?,
+        Format::Toml => toml::to_string_pretty(plan)?,
+        Format::Markdown => format!("# {}\n\n```jpc-plan yaml\n{}```\n", plan.description, serde_yaml::to_string(plan)?),
+    })
+}
+
+/// Replace the plan in `original` with `plan`, keeping the prose around a Markdown block.
+pub fn rewrite(original: &str, format: Format, plan: &Plan) -> Result<String> {
+    if format != Format::Markdown {
+        return render(plan, format);
+    }
+    let blocks = fences(original);
+    let [block] = blocks.as_slice() else {
+        anyhow::bail!("cannot rewrite a Markdown plan split over {} jpc-plan blocks", blocks.len());
+    };
+    let lines: Vec<&str> = original.split_inclusive('\n').collect();
+    let inner = block.format.unwrap_or(Format::Yaml);
+    let mut out: String = lines[..=block.open].concat();
+    out.push_str(&render(plan, inner)?);
+    out.push_str(&lines[block.close.min(lines.len())..].concat());
+    Ok(out)
+}
+
+/// A fenced `jpc-plan` block: the lines of its opening and closing fences, and the format
+/// named after `jpc-plan` in the info string, if any.
+struct Fence {
+    open: usize,
+    close: usize,
+    format: Option<Format>,
+    body: String,
+}
+
+fn fences(text: &str) -> Vec<Fence> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let t = lines[i].trim_start();
+        let marker: String = t.chars().take_while(|c| *c == '`' || *c == '~').collect();
+        let mut info = t[marker.len()..].split_whitespace();
+        let fence_char = marker.chars().next().unwrap_or('`');
+        let is_fence = marker.len() >= 3 && marker.chars().all(|c| c == fence_char);
+        if !is_fence || info.next() != Some("jpc-plan") {
+            i += 1;
+            continue;
+        }
+        let format = info.next().and_then(|f| Format::from_name(f).ok());
+        let close = (i + 1..lines.len())
+            .find(|&j| {
+                let c = lines[j].trim();
+                c.len() >= marker.len() && c.chars().all(|ch| ch == fence_char)
+            })
+            .unwrap_or(lines.len());
+        let body = lines[i + 1..close].iter().map(|l| format!("{}\n", l)).collect();
+        out.push(Fence { open: i, close, format, body });
+        i = close + 1;
+    }
+    out
+}
+
+/// Merge the `jpc-plan` blocks of a Markdown document: actions are concatenated in order,
+/// params merged, and other fields taken from the first block that has them. Without a
+/// `description`, the first heading of the document is used.
+fn markdown_to_value(text: &str) -> Result<Value> {
+    let blocks = fences(text);
+    if blocks.is_empty() {
+        anyhow::bail!("no ```jpc-plan fenced block found");
+    }
+    let mut plan = serde_json::Map::new();
+    for (n, b) in blocks.iter().enumerate() {
+        let format = b.format.unwrap_or_else(|| sniff(&b.body));
+        let value = to_value(&b.body, format)
+            .with_context(|| format!("jpc-plan block {} (line {}), parsed as {}", n + 1, b.open + 1, format.name()))?;
+        let Value::Object(obj) = value else {
+            anyhow::bail!("jpc-plan block {} (line {}) is not a plan object", n + 1, b.open + 1);
+        };
+        for (k, v) in obj {
+            match (plan.get_mut(&k), v) {
+                (Some(Value::Array(all)), Value::Array(more)) if k == "actions" => all.extend(more),
+                (Some(Value::Object(all)), Value::Object(more)) if k == "params" => all.extend(more),
+                (Some(_), _) => {}
+                (None, v) => { plan.insert(k, v); }
+            }
+        }
+    }
+    let outside = |i: usize| blocks.iter().all(|b| i < b.open || i > b.close);
+    let heading = text
+        .lines()
+        .enumerate()
+        .filter(|(i, _)| outside(*i))
+        .find_map(|(_, l)| l.strip_prefix('#').map(|h| h.trim_start_matches('#').trim()));
+    if !plan.contains_key("description") && let Some(h) = heading {
+        plan.insert("description".into(), h.into());
+    }
+    Ok(Value::Object(plan))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn plan() -> Plan {
+        serde_json::from_value(json!({
+            "version": 2,
+            "description": "Add a module",
+            "actions": [
+                { "type": "write_file", "path": "src/a.rs", "content": "fn a() {}\nfn b() {}\n" },
+                { "type": "run", "cmd": "cargo", "args": ["check"] },
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn detects_formats_by_extension_then_content() {
+        assert_eq!(Format::detect("plan.yml", "{}"), Format::Yaml);
+        assert_eq!(Format::detect("plan.MD", ""), Format::Markdown);
+        assert_eq!(Format::detect("-", "  {\"actions\": []}"), Format::Json);
+        assert_eq!(Format::detect("plan", "# Title\n\n```jpc-plan\nactions: []\n```\n"), Format::Markdown);
+        assert_eq!(Format::detect("plan", "version = 2\n[[actions]]\ntype = \"create_dir\"\n"), Format::Toml);
+        assert_eq!(Format::detect("plan", "version: 2\nactions:\n  - type: create_dir\n"), Format::Yaml);
+        assert!(Format::from_name("xml").is_err());
+    }
+
+    #[test]
+    fn parses_yaml_and_toml() {
+        let yaml = "version: 2\ndescription: d\nactions:\n  - type: create_dir\n    path: x\n";
+        let toml = "version = 2\ndescription = \"d\"\n[[actions]]\ntype = \"create_dir\"\npath = \"x\"\n";
+        let expected = json!({ "version": 2, "description": "d", "actions": [{ "type": "create_dir", "path": "x" }] });
+        assert_eq!(to_value(yaml, Format::Yaml).unwrap(), expected);
+        assert_eq!(to_value(toml, Format::Toml).unwrap(), expected);
+    }
+
+    #[test]
+    fn merges_tagged_and_untagged_markdown_blocks() {
+        let md = "# Tidy up\n\nFirst the directory:\n\n```jpc-plan json\n{\"version\": 2, \"actions\": [{\"type\": \"create_dir\", \"path\": \"x\"}]}\n```\n\n\
+                  Then the file:\n\n~~~~ jpc-plan\nactions:\n  - type: delete_file\n    path: y\n~~~~\n\n```rust\nfn not_a_plan() {}\n```\n";
+        let value = to_value(md, Format::Markdown).unwrap();
+        assert_eq!(value["description"], "Tidy up");
+        assert_eq!(value["version"], 2);
+        assert_eq!(value["actions"], json!([{ "type": "create_dir", "path": "x" }, { "type": "delete_file", "path": "y" }]));
+    }
+
+    #[test]
+    fn reports_bad_markdown_blocks() {
+        assert!(to_value("# Nothing here\n", Format::Markdown).unwrap_err().to_string().contains("no ```jpc-plan"));
+        let err = to_value("intro\n\n```jpc-plan json\n{ nope\n```\n", Format::Markdown).unwrap_err();
+        assert!(format!("{:#}", err).contains("jpc-plan block 1 (line 3), parsed as JSON"));
+        let err = to_value("```jpc-plan yaml\n- a list\n```\n", Format::Markdown).unwrap_err();
+        assert!(err.to_string().contains("is not a plan object"));
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        let expected = serde_json::to_value(plan()).unwrap();
+        for format in [Format::Json, Format::Yaml, Format::Toml, Format::Markdown] {
+            let text = render(&plan(), format).unwrap();
+            assert_eq!(Format::detect("-", &text), format, "{}", text);
+            assert_eq!(to_value(&text, format).unwrap(), expected, "{}", format.name());
+        }
+    }
+
+    #[test]
+    fn rewrites_the_markdown_block_in_place() {
+        let original = "# Add a module\n\nWhy:\n\n```jpc-plan json\n{}\n```\n\nDone.\n";
+        let text = rewrite(original, Format::Markdown, &plan()).unwrap();
+        assert!(text.starts_with("# Add a module\n\nWhy:\n\n```jpc-plan json\n{\n"));
+        assert!(text.ends_with("}\n```\n\nDone.\n"));
+        assert_eq!(to_value(&text, Format::Markdown).unwrap(), serde_json::to_value(plan()).unwrap());
+
+        let two = "```jpc-plan\nactions: []\n```\n```jpc-plan\nactions: []\n```\n";
+        assert!(rewrite(two, Format::Markdown, &plan()).unwrap_err().to_string().contains("split over 2 jpc-plan blocks"));
+        assert_eq!(rewrite("anything", Format::Yaml, &plan()).unwrap(), render(&plan(), Format::Yaml).unwrap());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::actions::{Action, Plan};
+use crate::diff;
+use crate::dry_run::Simulator;
+use crate::paths::PathGuard;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::process::Command;
+
+/// Walk the actions of `plan` one by one, showing what each would do, and return the plan
+/// made of the accepted (possibly edited) actions. Actions depending on a declined one are
+/// dropped with it.
+pub fn review(plan: &Plan, guard: &PathGuard) -> Result<Plan> {
+    let color = diff::use_color();
+    let mut sim = Simulator::new(guard, color);
+    let mut accepted: Vec<Action> = Vec::new();
+    let mut declined: HashSet<String> = HashSet::new();
+    let mut accept_rest = false;
+    let total = plan.actions.len();
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+
+    for (i, original) in plan.actions.iter().enumerate() {
+        let mut act = original.clone();
+        if let Some(dep) = act.depends_on.iter().find(|d| declined.contains(*d)) {
+            println!("[{}/{}] {} (dropped: depends on '{}', which was not accepted)", i + 1, total, act.short(), dep);
+            declined.extend(act.id.clone());
+            continue;
+        }
+        loop {
+            // Preview on a copy: a skipped action must not affect the following previews.
+            let mut trial = sim.clone();
+            let pv = trial.preview(&act);
+            if accept_rest {
+                println!("{}", diff::bold(&format!("[{}/{}] {} (accepted)", i + 1, total, act.short()), color));
+                accepted.push(act);
+                sim = trial;
+                break;
+            }
+            println!("{}", diff::bold(&format!("[{}/{}] {}", i + 1, total, act.short()), color));
+            print!("{}", pv.text);
+            print!("Apply this action? [y]es, [n]o/skip, [e]dit, [a]ccept all remaining, [q]uit (skip remaining): ");
+            io::stdout().flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                anyhow::bail!("stdin closed during interactive review; nothing applied");
+            }
+            match line.trim() {
+                "y" | "yes" => {
+                    accepted.push(act);
+                    sim = trial;
+                    break;
+                }
+                "n" | "no" | "s" => {
+                    declined.extend(act.id.clone());
+                    break;
+                }
+                "e" | "edit" => match edit_action(&act) {
+                    Ok(edited) => act = edited,
+                    Err(e) => println!("Edit discarded: {:#}", e),
+                },
+                "a" | "all" => accept_rest = true,
+                "q" | "quit" => {
+                    println!("Skipping the {} remaining action(s).", total - i);
+                    return Ok(Plan { actions: prune(accepted), ..plan.clone() });
+                }
+                other => println!("Unknown choice '{}'.", other),
+            }
+        }
+    }
+    Ok(Plan { actions: prune(accepted), ..plan.clone() })
+}
+
+/// Drop the accepted actions that depend, directly or through others, on one that was not
+/// accepted (a dependent listed before its dependency is only caught here), then the
+/// `on_failure` references to handlers that were not accepted.
+fn prune(mut actions: Vec<Action>) -> Vec<Action> {
+    loop {
+        let kept: HashSet<String> = actions.iter().filter_map(|a| a.id.clone()).collect();
+        let before = actions.len();
+        actions.retain(|a| match a.depends_on.iter().find(|d| !kept.contains(*d)) {
+            Some(dep) => {
+                println!("Dropped {}: depends on '{}', which was not accepted.", a.short(), dep);
+                false
+            }
+            None => true,
+        });
+        if actions.len() == before {
+            for a in &mut actions {
+                a.on_failure.retain(|h| kept.contains(h));
+            }
+            return actions;
+        }
+    }
+}
+
+/// Open the action as pretty JSON in `$EDITOR` (or `vi`) and parse it back.
+fn edit_action(act: &Action) -> Result<Action> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".into());
+    // A fresh, exclusively created file: a predictable name could be planted by another user.
+    let file = tempfile::Builder::new().prefix("jpc-action-").suffix(".json").tempfile()?;
+    fs::write(file.path(), serde_json::to_string_pretty(act)?)?;
+    let path = file.path();
+    // EDITOR may carry arguments, e.g. "code --wait".
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+    let status = Command::new(program)
+        .args(parts)
+        .arg(path)
+        .status()
+        .with_context(|| format!("launching editor '{}'", editor))?;
+    // Dropping `file` removes it.
+    let text = fs::read_to_string(path);
+    if !status.success() {
+        anyhow::bail!("editor exited with {}", status);
+    }
+    serde_json::from_str(&text?).context("parsing edited action JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Actions from `(id, depends_on, on_failure)`.
+    fn actions(spec: &[(&str, &[&str], &[&str])]) -> Vec<Action> {
+        spec.iter()
+            .map(|(id, deps, handlers)| {
+                serde_json::from_value(serde_json::json!({
+                    "type": "create_dir", "path": "x", "id": id, "depends_on": deps, "on_failure": handlers,
+                }))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    fn ids(actions: &[Action]) -> Vec<&str> {
+        actions.iter().filter_map(|a| a.id.as_deref()).collect()
+    }
+
+    #[test]
+    fn drops_dependents_of_declined_actions_transitively() {
+        // `build` was declined; `test` and `deploy` are listed before it and depend on it,
+        // `deploy` only through `test`.
+        let accepted = actions(&[("deploy", &["test"], &[]), ("test", &["build"], &[]), ("lint", &[], &[])]);
+        let pruned = prune(accepted);
+        assert_eq!(ids(&pruned), ["lint"]);
+        assert!(crate::schedule::order(&pruned).is_ok());
+    }
+
+    #[test]
+    fn drops_references_to_declined_handlers() {
+        let pruned = prune(actions(&[("build", &[], &["clean", "log"]), ("log", &[], &[])]));
+        assert_eq!(ids(&pruned), ["build", "log"]);
+        assert_eq!(pruned[0].on_failure, ["log"]);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::actions::Plan;
+use crate::config;
+use crate::files;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// One successful `jpc apply`, stored as `.jpc/journal/<id>.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: String,
+    pub timestamp: String,
+    pub plan: Plan,
+    pub changes: Vec<Change>,
+    #[serde(default)]
+    pub undone: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Change {
+    /// A file touched by the plan. A `None` hash means the file did not exist at that point.
+    File {
+        path: String,
+        before_hash: Option<String>,
+        after_hash: Option<String>,
+        backup: Option<String>,
+    },
+    Dir { path: String },
+    Moved { from: String, to: String },
+}
+
+/// A fresh, sortable apply id.
+pub fn new_id() -> String {
+    let base = chrono::Utc::now().format("%Y%m%d%H%M%S%3f").to_string();
+    let dir = Path::new(".jpc").join("journal");
+    let mut id = base.clone();
+    let mut n = 1;
+    while dir.join(format!("{}.json", id)).exists() {
+        id = format!("{}-{}", base, n);
+        n += 1;
+    }
+    id
+}
+
+pub fn hash_bytes(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+/// Hash of the file at `path`, or `None` if it is not a readable file.
+pub fn hash_file(path: &Path) -> Option<String> {
+    if !path.is_file() { return None; }
+    fs::read(path).ok().map(|d| hash_bytes(&d))
+}
+
+/// `path` relative to the working directory when it lives below it, as recorded in the journal.
+pub fn relative(path: &Path) -> String {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    path.strip_prefix(&cwd).unwrap_or(path).to_string_lossy().to_string()
+}
+
+/// `path` as a relative path safe to join under a backup directory: absolute prefixes and
+/// `..` components are replaced so a backup can never land outside of it.
+pub fn backup_name(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in Path::new(&relative(path)).components() {
+        match c {
+            Component::Normal(s) => out.push(s),
+            Component::ParentDir => out.push("_up_"),
+            Component::RootDir | Component::Prefix(_) => out.push("_root_"),
+            Component::CurDir => {}
+        }
+    }
+    out
+}
+
+pub fn save(entry: &JournalEntry) -> Result<()> {
+    let path = config::journal_dir()?.join(format!("{}.json", entry.id));
+    fs::write(&path, serde_json::to_string_pretty(entry)?)
+        .with_context(|| format!("writing journal entry {}", path.display()))
+}
+
+/// All journal entries, most recent first.
+pub fn load_all() -> Result<Vec<JournalEntry>> {
+    let dir = config::journal_dir()?;
+    let mut entries = Vec::new();
+    for e in fs::read_dir(&dir).with_context(|| format!("reading {}", dir.display()))? {
+        let p = e?.path();
+        if p.extension().and_then(|s| s.to_str()) != Some("json") { continue; }
+        let text = fs::read_to_string(&p).with_context(|| format!("reading {}", p.display()))?;
+        let entry: JournalEntry = serde_json::from_str(&text).with_context(|| format!("parsing {}", p.display()))?;
+        entries.push(entry);
+    }
+    entries.sort_by(|a, b| b.id.cmp(&a.id));
+    Ok(entries)
+}
+
+/// Undo the last `steps` applies that are not undone yet, most recent first.
+///
+/// Before touching anything, every file is checked against the hash recorded right after
+/// its apply; files edited since are reported and nothing is restored unless `force` is set.
+pub fn undo(steps: usize, force: bool) -> Result<Vec<JournalEntry>> {
+    let pending: Vec<JournalEntry> = load_all()?.into_iter().filter(|e| !e.undone).take(steps).collect();
+    if pending.is_empty() {
+        anyhow::bail!("nothing to undo");
+    }
+    let conflicts = conflicts(&pending);
+    if !conflicts.is_empty() && !force {
+        anyhow::bail!(
+            "cannot undo: files changed since they were applied (use --force to overwrite):\n  {}",
+            conflicts.join("\n  ")
+        );
+    }
+    let mut undone = Vec::new();
+    for mut entry in pending {
+        restore(&entry)?;
+        entry.undone = true;
+        save(&entry)?;
+        undone.push(entry);
+    }
+    Ok(undone)
+}
+
+/// Files of `pending` that no longer match their recorded state. An entry is checked against
+/// what undoing the more recent ones leaves behind, not against the disk as it is now.
+fn conflicts(pending: &[JournalEntry]) -> Vec<String> {
+    let mut expected: HashMap<&str, Option<String>> = HashMap::new();
+    let current = |expected: &HashMap<&str, Option<String>>, path: &str| match expected.get(path) {
+        Some(hash) => hash.clone(),
+        None => hash_file(Path::new(path)),
+    };
+    let mut found = Vec::new();
+    for entry in pending {
+        for change in &entry.changes {
+            if let Change::File { path, after_hash, .. } = change
+                && current(&expected, path) != *after_hash
+            {
+                found.push(format!("{} (apply {})", path, entry.id));
+            }
+        }
+        for change in entry.changes.iter().rev() {
+            match change {
+                Change::File { path, before_hash, .. } => {
+                    expected.insert(path, before_hash.clone());
+                }
+                Change::Moved { from, to } => {
+                    let hash = current(&expected, to);
+                    expected.insert(to, None);
+                    expected.insert(from, hash);
+                }
+                Change::Dir { .. } => {}
+            }
+        }
+    }
+    found
+}
+
+fn restore(entry: &JournalEntry) -> Result<()> {
+    for change in entry.changes.iter().rev() {
+        match change {
+            Change::File { path, before_hash: Some(_), backup: Some(backup), .. } => {
+                let p = Path::new(path);
+                if let Some(parent) = p.parent() { fs::create_dir_all(parent)?; }
+                let data = fs::read(backup).with_context(|| format!("reading backup {}", backup))?;
+                let perms = fs::metadata(backup).ok().map(|m| m.permissions());
+                files::write_atomic(p, &data, perms).with_context(|| format!("restoring {} from {}", path, backup))?;
+            }
+            Change::File { path, before_hash: None, .. } => {
+                let p = Path::new(path);
+                if p.exists() {
+                    fs::remove_file(p).with_context(|| format!("removing {}", path))?;
+                }
+            }
+            Change::File { path, .. } => anyhow::bail!("no backup recorded for {}", path),
+            Change::Dir { path } => {
+                // Only remove directories the plan created if nothing else lives there now.
+                let _ = fs::remove_dir(path);
+            }
+            Change::Moved { from, to } => {
+                fs::rename(to, from).with_context(|| format!("moving {} back to {}", to, from))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, changes: Vec<Change>) -> JournalEntry {
+        JournalEntry { id: id.into(), timestamp: String::new(), plan: Plan::default(), changes, undone: false }
+    }
+
+    fn file(path: &Path, before: &str, after: &str) -> Change {
+        Change::File {
+            path: path.to_string_lossy().to_string(),
+            before_hash: Some(hash_bytes(before.as_bytes())),
+            after_hash: Some(hash_bytes(after.as_bytes())),
+            backup: None,
+        }
+    }
+
+    #[test]
+    fn checks_older_entries_against_what_newer_ones_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.rs"), dir.path().join("b.rs"));
+        fs::write(&a, "v3").unwrap();
+        fs::write(&b, "edited").unwrap();
+        // Most recent first, as `undo` takes them.
+        let pending = [entry("2", vec![file(&a, "v2", "v3")]), entry("1", vec![file(&a, "v1", "v2"), file(&b, "x", "y")])];
+        let found = conflicts(&pending);
+        assert_eq!(found, [format!("{} (apply 1)", b.display())]);
+
+        fs::write(&a, "edited").unwrap();
+        let found = conflicts(&pending[..1]);
+        assert_eq!(found, [format!("{} (apply 2)", a.display())]);
+    }
+
+    #[test]
+    fn follows_moves() {
+        let dir = tempfile::tempdir().unwrap();
+        let (from, to) = (dir.path().join("old.rs"), dir.path().join("new.rs"));
+        fs::write(&to, "v2").unwrap();
+        let moved = Change::Moved { from: from.to_string_lossy().to_string(), to: to.to_string_lossy().to_string() };
+        let pending = [entry("2", vec![moved]), entry("1", vec![file(&from, "v1", "v2")])];
+        assert!(conflicts(&pending).is_empty());
+    }
+}
@@ -1,8 +1,12 @@
-use crate::actions::{Action, Plan};
+use crate::actions::{Action, ActionKind, Plan};
 use crate::config::Config;
 use crate::context::{self, ContextSnapshot};
+use crate::format::Format;
+use crate::migrate;
+use crate::validate;
 use anyhow::{Context as _, Result};
 use log::warn;
+use serde_json::Value;
 
 pub trait LocalPlanner {
     fn plan_actions(&self, ctx: &ContextSnapshot, query: &str, max_tokens: usize) -> Result<Plan>;
@@ -22,11 +26,12 @@ impl LocalPlanner for HeuristicPlanner {
 
         // Very naive heuristic: if the query mentions README, propose creating/updating a README.md.
         if query.to_lowercase().contains("readme") {
-            actions.push(Action::WriteFile {
+            actions.push(ActionKind::WriteFile {
                 path: "README.md".into(),
                 content: format!("# Project\n\nAutomated change requested: {}\n", query),
                 create_dirs: false,
-            });
+                expected_hash: None,
+            }.into());
         }
 
         let description = format!(
@@ -35,7 +40,7 @@ impl LocalPlanner for HeuristicPlanner {
             ctx.files.len()
         );
 
-        Ok(Plan { description, actions })
+        Ok(Plan { description, actions, ..Default::default() })
     }
 }
 
@@ -85,16 +90,15 @@ impl LocalPlanner for KalosmPlanner {
         });
 
         match result {
-            Ok(text) => {
-                if let Some(plan) = parse_plan_from_text(&text) {
-                    Ok(plan)
-                } else {
-                    warn!("Failed to parse JSON plan from Kalosm output. Falling back to heuristic actions.");
+            Ok(text) => match parse_plan_from_text(&text)? {
+                Some(plan) => Ok(plan),
+                None => {
+                    warn!("No JSON plan in Kalosm output. Falling back to heuristic actions.");
                     let mut plan = HeuristicPlanner.plan_actions(ctx, query, max_tokens)?;
                     plan.description = text;
                     Ok(plan)
                 }
-            }
+            },
             Err(e) => {
                 warn!("Kalosm generation error: {} — using heuristic planner", e);
                 HeuristicPlanner.plan_actions(ctx, query, max_tokens)
@@ -109,8 +113,12 @@ fn build_planner_prompt(query: &str, ctx_txt: &str) -> String {
         "You are Jean-Pierre, a precise code planning assistant.\n\
          Given the user's request and the project context, produce a plan strictly as a JSON object\n\
          with this schema and only this JSON as output (no markdown, no prose):\n\
-         {{\n  \"description\": \"<one sentence>\",\n  \"actions\": [\n    {{ \"type\": \"write_file\", \"path\": \"<string>\", \"content\": \"<string>\", \"create_dirs\": <bool> }},\n    {{ \"type\": \"run\", \"cmd\": \"<string>\", \"args\": [\"<string>\"] }}\n  ]\n}}\n\
+         {{\n  \"description\": \"<one sentence>\",\n  \"actions\": [\n    {{ \"type\": \"write_file\", \"path\": \"<string>\", \"content\": \"<string>\", \"create_dirs\": <bool> }},\n    {{ \"type\": \"edit_file\", \"path\": \"<string>\", \"edits\": [{{ \"old\": \"<exact existing text>\", \"new\": \"<replacement>\" }}] }},\n    {{ \"type\": \"insert_text\", \"path\": \"<string>\", \"text\": \"<lines to insert>\", \"after\": \"<anchor>\", \"regex\": <bool>, \"occurrence\": \"only|first|last\" }},\n    {{ \"type\": \"replace_rust_item\", \"path\": \"<.rs file>\", \"item\": \"<module::name or Type::method>\", \"kind\": \"fn|struct|enum|impl|method|...\", \"code\": \"<the whole new item>\", \"add\": <bool> }},\n    {{ \"type\": \"apply_patch\", \"patch\": \"<unified diff>\" }},\n    {{ \"type\": \"delete_file\", \"path\": \"<string>\" }},\n    {{ \"type\": \"rename_file\", \"from\": \"<string>\", \"to\": \"<string>\", \"overwrite\": <bool> }},\n    {{ \"type\": \"create_dir\", \"path\": \"<string>\" }},\n    {{ \"type\": \"run\", \"cmd\": \"<string>\", \"args\": [\"<string>\"], \"cwd\": \"<optional dir in the project>\", \"env\": {{ \"<NAME>\": \"<value>\" }}, \"clear_env\": <bool>, \"timeout_secs\": <optional int> }}\n  ]\n}}\n\
          Only include fields shown above. If no actions are necessary, use an empty array.\n\
+         Any action may also have \"id\", \"depends_on\": [ids that must succeed first], \"on_failure\": [ids to run if it fails] and \"continue_on_error\": true.\n\
+         Prefer edit_file over write_file for existing files; each \"old\" block must match the file exactly once.\n\
+         Use insert_text (with \"before\" or \"after\") to add lines next to an anchor, e.g. a `use` after the last import.\n\
+         In Rust files, prefer replace_rust_item to rewrite a whole function, struct, enum or impl; with \"add\": true it adds \"code\" to the impl block named by \"item\".\n\
          User request: \n{}\n\
          Project context (truncated):\n{}",
         query,
@@ -118,12 +126,37 @@ fn build_planner_prompt(query: &str, ctx_txt: &str) -> String {
     )
 }
 
-fn parse_plan_from_text(text: &str) -> Option<Plan> {
+/// The plan in the model's output: the whole text, or else the first JSON object in it;
+/// `None` if there is none. Models like to add fields of their own, so fields the plan
+/// format does not know are dropped with a warning. Any other problem is an error, listing
+/// them all.
+fn parse_plan_from_text(text: &str) -> Result<Option<Plan>> {
+    let Some(mut value) = find_json(text) else { return Ok(None) };
+    // The prompt describes the current format, which older versions would not upgrade to.
+    if let Some(obj) = value.as_object_mut() {
+        obj.entry("version").or_insert(migrate::CURRENT_VERSION.into());
+    }
+    let (_, problems) = validate::validate(&value.to_string(), Format::Json);
+    for p in problems.iter().filter(|p| p.message.starts_with("unknown field")) {
+        warn!("Ignoring {} in the model's plan", p.path);
+        remove(&mut value, &p.path);
+    }
+    match validate::validate(&value.to_string(), Format::Json) {
+        (Some(plan), problems) if problems.is_empty() => Ok(Some(plan)),
+        (_, problems) => anyhow::bail!(
+            "the model's plan is invalid ({} problem(s)):\n  {}",
+            problems.len(),
+            problems.iter().map(|p| format!("{}: {}", p.path, p.message)).collect::<Vec<_>>().join("\n  ")
+        ),
+    }
+}
+
+fn find_json(text: &str) -> Option<Value> {
     // 1) Try direct parse.
-    if let Ok(plan) = serde_json::from_str::<Plan>(text) {
-        return Some(plan);
+    if let Ok(value) = serde_json::from_str(text) {
+        return Some(value);
     }
-    // 2) Try to extract the largest JSON object substring.
+    // 2) Try to extract the first balanced JSON object.
     let bytes = text.as_bytes();
     let mut best: Option<(usize, usize)> = None;
     for i in 0..bytes.len() {
@@ -145,14 +178,49 @@ fn parse_plan_from_text(text: &str) -> Option<Plan> {
             if best.is_some() { break; }
         }
     }
-    if let Some((s, e)) = best {
-        let slice = &text[s..e];
-        if let Ok(plan) = serde_json::from_str::<Plan>(slice) {
-            return Some(plan);
-        }
+    let (s, e) = best?;
+    serde_json::from_str(&text[s..e]).ok()
+}
+
+/// Remove the field at a validation path such as `$.actions[0].reason`.
+fn remove(value: &mut Value, path: &str) {
+    let pointer = path.trim_start_matches('$').replace('[', ".").replace(']', "").replace('.', "/");
+    let Some((parent, key)) = pointer.rsplit_once('/') else { return };
+    if let Some(obj) = value.pointer_mut(parent).and_then(Value::as_object_mut) {
+        obj.remove(key);
     }
-    None
 }
 
 // Future: when the `local-llm` feature is enabled and Candle is integrated, this module can
 // switch from calling `ollama` to a fully in-process Candle-backed model using GGUF weights.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_plan_in_prose() {
+        let text = "Sure! Here is the plan:\n{\"description\": \"d\", \"actions\": [{\"type\": \"create_dir\", \"path\": \"x\"}]}\nDone.";
+        let plan = parse_plan_from_text(text).unwrap().unwrap();
+        assert_eq!(plan.version, migrate::CURRENT_VERSION);
+        assert_eq!(plan.actions.len(), 1);
+        assert!(parse_plan_from_text("I cannot help with that.").unwrap().is_none());
+    }
+
+    #[test]
+    fn drops_fields_the_format_does_not_know() {
+        let text = r#"{"description": "d", "reasoning": "r", "actions": [{"type": "run", "cmd": "ls", "args": [], "cwd": "src", "why": "look"}]}"#;
+        let plan = parse_plan_from_text(text).unwrap().unwrap();
+        let ActionKind::Run { cwd, .. } = &plan.actions[0].kind else { panic!("not a run action") };
+        assert_eq!(cwd.as_deref(), Some("src"));
+    }
+
+    #[test]
+    fn reports_other_problems() {
+        let text = r#"{"description": "d", "actions": [{"type": "teleport"}, {"type": "delete_file"}]}"#;
+        let err = parse_plan_from_text(text).unwrap_err().to_string();
+        assert!(err.starts_with("the model's plan is invalid (2 problem(s))"), "{}", err);
+        assert!(err.contains("$.actions[0].type: unknown action type 'teleport'"), "{}", err);
+        assert!(err.contains("$.actions[1]: missing required field 'path'"), "{}", err);
+    }
+}
@@ -5,9 +5,30 @@ mod local_llm;
 mod remote;
 mod actions;
 mod exec;
+mod edit;
+mod patch;
+mod transaction;
+mod journal;
+mod paths;
+mod diff;
+mod dry_run;
+mod interactive;
+mod stale;
+mod validate;
+mod template;
+mod schedule;
+mod report;
+mod migrate;
+mod format;
+mod rust_edit;
+mod verify;
+mod files;
+mod merge;
+mod policy;
+mod sandbox;
 
 use anyhow::{Context as _, Result};
-use cli::{Cli, Commands};
+use cli::{Cli, Commands, PlanCommand};
 use clap::Parser;
 use log::{debug, info};
 
@@ -24,26 +45,193 @@ fn main() -> Result<()> {
             let ctx = context::gather_context(max_files, max_bytes)?;
             println!("{}", serde_json::to_string_pretty(&ctx)?);
         }
-        Commands::Plan { query, max_tokens } => {
+        Commands::Plan { command: Some(PlanCommand::Schema), .. } => {
+            println!("{}", serde_json::to_string_pretty(&validate::schema())?);
+        }
+        Commands::Plan { command: Some(PlanCommand::Validate { file }), .. } => {
+            let text = std::fs::read_to_string(&file)
+                .with_context(|| format!("reading plan file: {}", file))?;
+            let fmt = format::Format::detect(&file, &text);
+            let (_, problems, upgrade) = validate::validate_upgrading(&text, fmt);
+            if let Some(upgrade) = upgrade {
+                println!("note: {}", upgrade.note(&file));
+            }
+            if problems.is_empty() {
+                println!("{}: valid plan.", file);
+            } else {
+                for p in &problems {
+                    println!("{}", p.render(&file));
+                }
+                anyhow::bail!("{} problem(s) found in {}", problems.len(), file);
+            }
+        }
+        Commands::Plan { command: Some(PlanCommand::Upgrade { file }), .. } => {
+            let text = std::fs::read_to_string(&file)
+                .with_context(|| format!("reading plan file: {}", file))?;
+            let fmt = format::Format::detect(&file, &text);
+            let value = format::to_value(&text, fmt).with_context(|| format!("parsing plan file: {}", file))?;
+            let from = migrate::version_of(&value)?;
+            if from == migrate::CURRENT_VERSION {
+                println!("{} is already at plan format version {}.", file, from);
+                return Ok(());
+            }
+            // Refuse to rewrite a plan that would still not load.
+            let (plan, problems, upgrade) = validate::validate_upgrading(&text, fmt);
+            let (Some(plan), Some(upgrade)) = (plan, upgrade) else {
+                for p in &problems {
+                    println!("{}", p.render(&file));
+                }
+                anyhow::bail!("{} problem(s) left after upgrading {}; nothing was written", problems.len(), file);
+            };
+            std::fs::write(&file, format::rewrite(&text, fmt, &plan)?)
+                .with_context(|| format!("writing {}", file))?;
+            for n in &upgrade.notes {
+                println!("  {}", n);
+            }
+            println!("Upgraded {} from plan format version {} to {}.", file, upgrade.from, migrate::CURRENT_VERSION);
+        }
+        Commands::Plan { command: Some(PlanCommand::Convert { file, to, output }), .. } => {
+            let text = std::fs::read_to_string(&file)
+                .with_context(|| format!("reading plan file: {}", file))?;
+            let plan = validate::load_plan(&text, &file)?;
+            let fmt = match (&to, &output) {
+                (Some(name), _) => format::Format::from_name(name)?,
+                (None, Some(out)) => format::Format::from_path(out)
+                    .with_context(|| format!("cannot tell the format of {} from its extension; pass --to", out))?,
+                (None, None) => anyhow::bail!("pass --to FORMAT or --output FILE"),
+            };
+            let rendered = format::render(&plan, fmt)?;
+            match output {
+                Some(out) => {
+                    std::fs::write(&out, rendered).with_context(|| format!("writing {}", out))?;
+                    println!("Wrote {} as {}.", out, fmt.name());
+                }
+                None => print!("{}", rendered),
+            }
+        }
+        Commands::Plan { command: Some(PlanCommand::Merge { files, output }), .. } => {
+            let mut plans = Vec::new();
+            for file in &files {
+                let text = std::fs::read_to_string(file)
+                    .with_context(|| format!("reading plan file: {}", file))?;
+                plans.push((file.clone(), validate::load_plan(&text, file)?));
+            }
+            let cfg = config::load_config()?;
+            let guard = paths::PathGuard::from_config(&cfg, false)?;
+            let merged = merge::merge(plans, &guard)?;
+            for n in &merged.notes {
+                eprintln!("note: {}", n);
+            }
+            let fmt = output.as_deref().and_then(format::Format::from_path).unwrap_or(format::Format::Json);
+            let rendered = format::render(&merged.plan, fmt)?;
+            match output {
+                Some(out) => {
+                    std::fs::write(&out, rendered).with_context(|| format!("writing {}", out))?;
+                    println!("Merged {} plans into {} ({} actions).", files.len(), out, merged.plan.actions.len());
+                }
+                None => print!("{}", rendered),
+            }
+        }
+        Commands::Plan { command: None, query, max_tokens } => {
+            let query = query.unwrap_or_default();
             let cfg = config::load_config()?;
             let ctx = context::gather_context(None, None)?;
             let planner = local_llm::build_local_llm(&cfg)?;
-            let plan = planner.plan_actions(&ctx, &query, max_tokens.unwrap_or(2048))?;
+            let mut plan = planner.plan_actions(&ctx, &query, max_tokens.unwrap_or(2048))?;
+            // Parsed with the current `Plan` type, so it is in the current format.
+            plan.version = migrate::CURRENT_VERSION;
+            plan.stamp_expected_hashes(&ctx);
             println!("{}", serde_json::to_string_pretty(&plan)?);
         }
-        Commands::Apply { plan_file, dry_run } => {
+        Commands::Apply {
+            plan_file,
+            vars,
+            dry_run,
+            interactive,
+            merge_stale,
+            allow_outside_root,
+            report,
+            no_verify,
+            keep_on_failure,
+            sandbox,
+        } => {
             let text = std::fs::read_to_string(&plan_file)
                 .with_context(|| format!("reading plan file: {}", plan_file))?;
-            let actions: actions::Plan = serde_json::from_str(&text)
-                .with_context(|| "parsing plan JSON")?;
+            let actions = validate::load_plan(&text, &plan_file)?;
+            let actions = template::instantiate(&actions, &template::parse_vars(&vars)?)?;
+            let cfg = config::load_config()?;
+            let guard = paths::PathGuard::from_config(&cfg, allow_outside_root)?;
+            let policy = cfg.policy.clone().unwrap_or_default();
             if dry_run {
-                println!("Would apply {} actions:", actions.actions.len());
-                for (i, a) in actions.actions.iter().enumerate() {
-                    println!("{:03}: {}", i + 1, a.short());
+                dry_run::print_plan(&actions, &guard);
+                for p in policy::violations(&actions, &policy)? {
+                    println!("policy: {}", p);
+                }
+                return Ok(());
+            }
+            let plan = if interactive {
+                let reviewed = interactive::review(&actions, &guard)?;
+                if reviewed.actions.is_empty() {
+                    println!("No actions accepted; nothing applied.");
+                    return Ok(());
                 }
+                reviewed
             } else {
-                actions::apply_plan(&actions)?;
-                println!("Applied {} actions.", actions.actions.len());
+                actions.clone()
+            };
+            let verify = if no_verify { None } else { verify::Verify::from_config(&cfg, guard.root(), keep_on_failure) };
+            let opts = actions::ApplyOptions {
+                guard,
+                merge_stale,
+                verify,
+                run_timeout: config::run_timeout(&cfg),
+                policy,
+                sandbox: sandbox || cfg.sandbox.unwrap_or(false),
+            };
+            let mut rep = report::ApplyReport::new(&plan);
+            let res = actions::apply_plan(&plan, &opts, &mut rep);
+            rep.finish(&res);
+            print!("{}", rep.table());
+            if let Some(path) = &report {
+                rep.write(path)?;
+            }
+            res?;
+            let applied = rep.count(report::Status::Applied);
+            let run = rep.actions.len() - rep.count(report::Status::NotNeeded);
+            if interactive {
+                let out = std::path::Path::new(&plan_file).with_extension("applied.json");
+                std::fs::write(&out, serde_json::to_string_pretty(&plan)?)
+                    .with_context(|| format!("writing {}", out.display()))?;
+                println!("Applied {} of {} actions; applied plan saved to {}.", applied, actions.actions.len(), out.display());
+            } else if applied == run {
+                println!("Applied {} actions.", applied);
+            } else {
+                println!("Applied {} of {} actions.", applied, run);
+            }
+        }
+        Commands::Undo { steps, force } => {
+            for entry in journal::undo(steps, force)? {
+                println!("Undid apply {} ({}): {}", entry.id, entry.timestamp, entry.plan.description);
+            }
+        }
+        Commands::History { limit } => {
+            let entries = journal::load_all()?;
+            if entries.is_empty() {
+                println!("No applies recorded.");
+            }
+            // Number pending applies the way `jpc undo --steps N` counts them.
+            let mut step = 0;
+            for e in entries.iter().take(limit.unwrap_or(usize::MAX)) {
+                let label = if e.undone { "undone".to_string() } else { step += 1; step.to_string() };
+                println!(
+                    "{:>6}  {}  {}  {} actions, {} changes  {}",
+                    label,
+                    e.id,
+                    e.timestamp,
+                    e.plan.actions.len(),
+                    e.changes.len(),
+                    e.plan.description
+                );
             }
         }
         Commands::Chat { prompt } => {
@@ -62,7 +250,7 @@ fn main() -> Result<()> {
             match what.as_str() {
                 "build" => exec::cargo_build(&[])?,
                 "test" => exec::cargo_test(&[])?,
-                other => exec::run_cmd(other, &[])?,
+                other => exec::run_cmd(other, &[], &exec::RunOptions::default())?,
             }
         }
     }
@@ -0,0 +1,406 @@
+use crate::actions::{Action, ActionKind, Plan};
+use crate::edit;
+use crate::files::{self, Style};
+use crate::journal;
+use crate::migrate;
+use crate::patch;
+use crate::paths::PathGuard;
+use crate::rust_edit;
+use crate::schedule;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A merged plan and what was done to produce it.
+pub struct Merged {
+    pub plan: Plan,
+    pub notes: Vec<String>,
+}
+
+/// Concatenate `plans` (named by their file) into one plan.
+///
+/// Ids used by several plans are renamed in the later ones. Files changed by more than one
+/// plan are checked by applying each plan's changes to the current file and merging the
+/// results three ways: when the changes do not overlap but the concatenated actions would not
+/// produce that merge (e.g. two `write_file`s), they are replaced by a single `write_file` of
+/// the merged content. Overlapping changes, and files deleted or moved by one plan and
+/// changed by another, fail the merge with a report of every conflict.
+pub fn merge(plans: Vec<(String, Plan)>, guard: &PathGuard) -> Result<Merged> {
+    let mut notes = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut out = Plan {
+        version: migrate::CURRENT_VERSION,
+        description: plans.iter().map(|(_, p)| p.description.trim()).filter(|d| !d.is_empty()).collect::<Vec<_>>().join("; "),
+        ..Plan::default()
+    };
+    // Plan number and position in that plan of every merged action.
+    let mut origin: Vec<(usize, usize)> = Vec::new();
+    let mut used: HashSet<String> = HashSet::new();
+    for (n, (name, plan)) in plans.iter().enumerate() {
+        for (k, param) in &plan.params {
+            match out.params.get(k) {
+                Some(p) if p != param => conflicts.push(format!("param '{}' is declared differently by {} and an earlier plan", k, name)),
+                Some(_) => {}
+                None => { out.params.insert(k.clone(), param.clone()); }
+            }
+        }
+        let renames = rename_ids(&plan.actions, &mut used, n + 1);
+        for (from, to) in &renames {
+            notes.push(format!("renamed id '{}' of {} to '{}' (already used by an earlier plan)", from, name, to));
+        }
+        for (i, act) in plan.actions.iter().enumerate() {
+            let mut act = act.clone();
+            relabel(&mut act, &renames);
+            out.actions.push(act);
+            origin.push((n, i));
+        }
+    }
+    let label = |j: usize, actions: &[Action]| {
+        let (n, i) = origin[j];
+        format!("{} action {}", plans[n].0, actions[j].label(i))
+    };
+
+    // Which actions change each file, in order.
+    let mut by_file: BTreeMap<PathBuf, Vec<usize>> = BTreeMap::new();
+    for (j, act) in out.actions.iter().enumerate() {
+        if matches!(act.kind, ActionKind::CreateDir { .. } | ActionKind::Run { .. }) { continue; }
+        for p in act.paths().iter().filter_map(|p| guard.resolve(p).ok()) {
+            let list = by_file.entry(p).or_default();
+            if !list.contains(&j) { list.push(j); }
+        }
+    }
+
+    let mut replace: Vec<(PathBuf, Vec<usize>, String, Option<String>)> = Vec::new();
+    for (file, idxs) in &by_file {
+        let plans_here: Vec<usize> = idxs.iter().map(|&j| origin[j].0).collect::<BTreeSet<_>>().into_iter().collect();
+        if plans_here.len() < 2 { continue; }
+        let rel = guard.relative(file);
+        let structural: Vec<usize> = idxs
+            .iter()
+            .copied()
+            .filter(|&j| moves(&out.actions[j], file, guard))
+            .collect();
+        if let Some(&s) = structural.first() {
+            let others: Vec<String> = idxs.iter().filter(|&&j| origin[j].0 != origin[s].0).map(|&j| label(j, &out.actions)).collect();
+            conflicts.push(format!("{}: {} deletes or moves it, but {} also changes it", rel, label(s, &out.actions), others.join(", ")));
+            continue;
+        }
+
+        let raw = fs::read(file).ok();
+        let base = raw.as_ref().and_then(|b| String::from_utf8(b.clone()).ok()).map(|t| files::decode(&t));
+        let run = |js: &[usize]| -> Result<Option<String>> {
+            let mut cur = base.clone();
+            for &j in js {
+                cur = simulate(&out.actions[j], file, guard, cur).with_context(|| label(j, &out.actions))?;
+            }
+            Ok(cur)
+        };
+        let mut results = Vec::new();
+        let mut failed = false;
+        for &n in &plans_here {
+            let own: Vec<usize> = idxs.iter().copied().filter(|&j| origin[j].0 == n).collect();
+            match run(&own) {
+                Ok(r) => results.push((plans[n].0.clone(), r)),
+                Err(e) => {
+                    conflicts.push(format!("{}: does not apply to the current file: {:#}", rel, e));
+                    failed = true;
+                }
+            }
+        }
+        if failed { continue; }
+        let merged = match merge_results(base.as_deref(), &results) {
+            Ok(m) => m,
+            Err(report) => {
+                let who: Vec<String> = idxs.iter().map(|&j| label(j, &out.actions)).collect();
+                conflicts.push(format!("{}: {} change the same lines:\n{}", rel, who.join(", "), report));
+                continue;
+            }
+        };
+        if run(idxs).ok().as_ref() == Some(&merged) { continue; }
+        let Some(content) = merged else {
+            conflicts.push(format!("{}: removed by one plan and changed by another", rel));
+            continue;
+        };
+        let multi = idxs.iter().find(|&&j| out.actions[j].paths().iter().filter_map(|p| guard.resolve(p).ok()).any(|p| p != *file));
+        if let Some(&j) = multi {
+            conflicts.push(format!(
+                "{}: the changes do not overlap, but {} also changes other files, so they cannot be combined into one write",
+                rel,
+                label(j, &out.actions)
+            ));
+            continue;
+        }
+        notes.push(format!(
+            "{}: combined non-overlapping changes of {} into one write_file",
+            rel,
+            idxs.iter().map(|&j| label(j, &out.actions)).collect::<Vec<_>>().join(", ")
+        ));
+        replace.push((file.clone(), idxs.clone(), content, raw.as_deref().map(journal::hash_bytes)));
+    }
+
+    if !conflicts.is_empty() {
+        anyhow::bail!("{} conflict(s), nothing was merged:\n  {}", conflicts.len(), conflicts.join("\n  "));
+    }
+    let mut dropped = HashSet::new();
+    for (file, idxs, content, hash) in replace {
+        combine(&mut out.actions, &idxs, guard.relative(&file), content, hash);
+        dropped.extend(idxs[..idxs.len() - 1].iter().copied());
+    }
+    let mut j = 0;
+    out.actions.retain(|_| {
+        j += 1;
+        !dropped.contains(&(j - 1))
+    });
+    let problems = schedule::problems(&out.actions);
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "the merged plan has invalid dependencies:\n  {}",
+            problems.iter().map(|(i, f, m)| format!("action {} {}: {}", out.actions[*i].label(*i), f, m)).collect::<Vec<_>>().join("\n  ")
+        );
+    }
+    Ok(Merged { plan: out, notes })
+}
+
+/// New ids for the ids of `actions` that are already in `used`, and record the others.
+fn rename_ids(actions: &[Action], used: &mut HashSet<String>, plan_no: usize) -> HashMap<String, String> {
+    let mut renames = HashMap::new();
+    let own: HashSet<&str> = actions.iter().filter_map(|a| a.id.as_deref()).collect();
+    for id in actions.iter().filter_map(|a| a.id.as_ref()) {
+        if !used.contains(id) { continue; }
+        let mut n = plan_no;
+        let new = loop {
+            let candidate = format!("{}-{}", id, n);
+            if !used.contains(&candidate) && !own.contains(candidate.as_str()) { break candidate; }
+            n += 1;
+        };
+        renames.insert(id.clone(), new);
+    }
+    for id in actions.iter().filter_map(|a| a.id.as_ref()) {
+        used.insert(renames.get(id).unwrap_or(id).clone());
+    }
+    renames
+}
+
+fn relabel(act: &mut Action, renames: &HashMap<String, String>) {
+    let rename = |s: &mut String| if let Some(n) = renames.get(s.as_str()) { *s = n.clone(); };
+    if let Some(id) = &mut act.id { rename(id); }
+    act.depends_on.iter_mut().for_each(rename);
+    act.on_failure.iter_mut().for_each(rename);
+}
+
+/// Whether `act` deletes or moves `file` rather than changing its content.
+fn moves(act: &Action, file: &Path, guard: &PathGuard) -> bool {
+    match &act.kind {
+        ActionKind::DeleteFile { .. } | ActionKind::RenameFile { .. } => true,
+        ActionKind::ApplyPatch { patch: text, .. } => patch::parse(text).is_ok_and(|p| {
+            p.files.iter().any(|f| {
+                let (Some(old), Some(new)) = (&f.old_path, &f.new_path) else { return false };
+                let (old, new) = (guard.resolve(old).ok(), guard.resolve(new).ok());
+                old != new && (old.as_deref() == Some(file) || new.as_deref() == Some(file))
+            })
+        }),
+        _ => false,
+    }
+}
+
+/// Content of `file` after `act`, given its content before (without BOM and with LF line
+/// endings, as edits see it).
+fn simulate(act: &Action, file: &Path, guard: &PathGuard, cur: Option<String>) -> Result<Option<String>> {
+    let existing = |path: &str| cur.clone().with_context(|| format!("{} does not exist", path));
+    Ok(match &act.kind {
+        ActionKind::WriteFile { content, .. } => Some(files::decode(content)),
+        ActionKind::EditFile { path, edits, .. } => Some(edit::apply_edits(path, &existing(path)?, edits)?),
+        ActionKind::InsertText { path, text, before, after, regex, occurrence, .. } => {
+            Some(edit::insert_text(path, &existing(path)?, text, before.as_deref(), after.as_deref(), *regex, *occurrence)?)
+        }
+        ActionKind::ReplaceRustItem { path, item, kind, code, add, .. } => {
+            Some(rust_edit::replace_item(path, &existing(path)?, item, *kind, code, *add)?)
+        }
+        ActionKind::ApplyPatch { patch: text, fuzz, max_offset, .. } => {
+            let parsed = patch::parse(text).context("parsing unified diff")?;
+            let Some(fp) = parsed.files.iter().find(|f| guard.resolve(f.target()).is_ok_and(|p| p == file)) else { return Ok(cur) };
+            match &fp.new_path {
+                Some(_) => Some(patch::apply_file(fp, cur.as_deref().unwrap_or(""), *fuzz, *max_offset)?),
+                None => None,
+            }
+        }
+        ActionKind::DeleteFile { .. } | ActionKind::RenameFile { .. } => None,
+        ActionKind::CreateDir { .. } | ActionKind::Run { .. } => cur,
+    })
+}
+
+/// Three-way merge each plan's version of a file into the base, or the conflicting hunks.
+fn merge_results(base: Option<&str>, results: &[(String, Option<String>)]) -> std::result::Result<Option<String>, String> {
+    let (first_name, first) = &results[0];
+    let mut acc = first.clone();
+    let mut names = first_name.clone();
+    for (name, r) in &results[1..] {
+        acc = match (base, &acc, r) {
+            (_, a, b) if a == b => acc,
+            (Some(base), Some(a), Some(b)) => match diffy::merge(base, a, b) {
+                Ok(m) => Some(m),
+                Err(marked) => return Err(hunks(&marked, &names, name)),
+            },
+            (None, Some(_), Some(_)) => return Err(format!("    {} and {} both create it with different content", names, name)),
+            _ => return Err(format!("    {} and {} disagree on whether it exists", names, name)),
+        };
+        names = format!("{} + {}", names, name);
+    }
+    Ok(acc)
+}
+
+/// The conflict blocks of a diffy merge, labelled with the plans and indented.
+fn hunks(marked: &str, ours: &str, theirs: &str) -> String {
+    let mut out = Vec::new();
+    let mut inside = false;
+    for line in marked.lines() {
+        if line.starts_with("<<<<<<<") {
+            inside = true;
+            out.push(format!("    <<<<<<< {}", ours));
+        } else if line.starts_with(">>>>>>>") {
+            inside = false;
+            out.push(format!("    >>>>>>> {}", theirs));
+        } else if line.starts_with("|||||||") {
+            out.push("    ||||||| current file".into());
+        } else if inside {
+            out.push(format!("    {}", line));
+        }
+    }
+    out.join("\n")
+}
+
+/// Turn the last of the actions `idxs` (all changing `path` only) into one `write_file` of
+/// `content` that inherits their ids, dependencies and handlers. The others are left for the
+/// caller to drop.
+fn combine(actions: &mut [Action], idxs: &[usize], path: String, content: String, hash: Option<String>) {
+    let last = *idxs.last().expect("at least two actions");
+    let ids: Vec<String> = idxs.iter().filter_map(|&j| actions[j].id.clone()).collect();
+    let new_id = actions[last].id.clone().or_else(|| ids.first().cloned());
+    let mut act = Action {
+        id: new_id.clone(),
+        depends_on: Vec::new(),
+        on_failure: Vec::new(),
+        continue_on_error: idxs.iter().all(|&j| actions[j].continue_on_error),
+        style: Style::default(),
+        kind: ActionKind::WriteFile { path, content, create_dirs: true, expected_hash: hash },
+    };
+    for &j in idxs {
+        let a = &actions[j];
+        if !a.style.is_keep() { act.style = a.style.clone(); }
+        for d in &a.depends_on {
+            if !ids.contains(d) && !act.depends_on.contains(d) { act.depends_on.push(d.clone()); }
+        }
+        for h in &a.on_failure {
+            if !act.on_failure.contains(h) { act.on_failure.push(h.clone()); }
+        }
+    }
+    // References to the replaced actions now point to the combined one.
+    if let Some(new_id) = &new_id {
+        for a in actions.iter_mut() {
+            for r in a.depends_on.iter_mut().chain(a.on_failure.iter_mut()) {
+                if ids.contains(r) { *r = new_id.clone(); }
+            }
+            let mut seen = HashSet::new();
+            a.depends_on.retain(|r| seen.insert(r.clone()));
+            let mut seen = HashSet::new();
+            a.on_failure.retain(|r| seen.insert(r.clone()));
+        }
+    }
+    actions[last] = act;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const BASE: &str = "fn a() {}\n\nfn b() {}\n\nfn c() {}\n";
+
+    fn project() -> (tempfile::TempDir, PathGuard) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("lib.rs"), BASE).unwrap();
+        let guard = PathGuard::new(dir.path(), &[], false).unwrap();
+        (dir, guard)
+    }
+
+    fn plan(name: &str, actions: serde_json::Value) -> (String, Plan) {
+        let plan = serde_json::from_value(json!({ "version": 2, "description": name, "actions": actions })).unwrap();
+        (format!("{}.json", name), plan)
+    }
+
+    fn conflicts(plans: Vec<(String, Plan)>, guard: &PathGuard) -> String {
+        match merge(plans, guard) {
+            Ok(_) => panic!("merged without conflicts"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    fn edit(id: &str, old: &str, new: &str) -> serde_json::Value {
+        json!({ "type": "edit_file", "id": id, "path": "lib.rs", "edits": [{ "old": old, "new": new }] })
+    }
+
+    #[test]
+    fn combines_changes_that_do_not_overlap() {
+        let (_dir, guard) = project();
+        // Run one after the other, the write_file would undo the edit.
+        let one = plan("one", json!([edit("e", "fn a() {}", "fn a2() {}")]));
+        let two = plan("two", json!([{ "type": "write_file", "path": "lib.rs", "content": BASE.replace("fn c()", "fn c2()") }]));
+        let merged = merge(vec![one, two], &guard).unwrap();
+        assert_eq!(merged.plan.description, "one; two");
+        assert_eq!(merged.plan.actions.len(), 1);
+        let ActionKind::WriteFile { content, expected_hash, .. } = &merged.plan.actions[0].kind else { panic!("not a write_file") };
+        assert_eq!(content, "fn a2() {}\n\nfn b() {}\n\nfn c2() {}\n");
+        assert_eq!(expected_hash.as_deref(), Some(journal::hash_bytes(BASE.as_bytes()).as_str()));
+        assert_eq!(merged.plan.actions[0].id.as_deref(), Some("e"));
+        assert!(merged.notes[0].contains("combined non-overlapping changes"));
+    }
+
+    #[test]
+    fn keeps_edits_that_already_compose() {
+        let (_dir, guard) = project();
+        let one = plan("one", json!([edit("x", "fn a()", "fn a2()")]));
+        let two = plan("two", json!([edit("x", "fn b()", "fn b2()"), { "type": "run", "cmd": "cargo", "args": ["check"], "depends_on": ["x"] }]));
+        let merged = merge(vec![one, two], &guard).unwrap();
+        let ids: Vec<_> = merged.plan.actions.iter().map(|a| a.id.as_deref()).collect();
+        assert_eq!(ids, [Some("x"), Some("x-2"), None]);
+        assert_eq!(merged.plan.actions[2].depends_on, ["x-2"]);
+        assert!(merged.notes[0].contains("renamed id 'x' of two.json to 'x-2'"));
+    }
+
+    #[test]
+    fn reports_every_conflict() {
+        let (dir, guard) = project();
+        fs::write(dir.path().join("other.rs"), "x\n").unwrap();
+        let one = plan("one", json!([edit("a", "fn b() {}", "fn b1() {}"), { "type": "delete_file", "path": "other.rs" }]));
+        let two = plan("two", json!([
+            edit("b", "fn b() {}", "fn b2() {}"),
+            { "type": "write_file", "path": "other.rs", "content": "y\n" },
+        ]));
+        let err = conflicts(vec![one, two], &guard);
+        assert!(err.starts_with("2 conflict(s), nothing was merged"), "{}", err);
+        assert!(err.contains("lib.rs: one.json action 001 'a'") && err.contains("change the same lines"), "{}", err);
+        assert!(err.contains("<<<<<<< one.json") && err.contains("fn b1() {}") && err.contains(">>>>>>> two.json"), "{}", err);
+        assert!(err.contains("other.rs: one.json action 002 (delete_file:other.rs) deletes or moves it, but two.json action 002"), "{}", err);
+    }
+
+    #[test]
+    fn reports_changes_that_no_longer_apply() {
+        let (_dir, guard) = project();
+        let one = plan("one", json!([edit("a", "fn a()", "fn a2()")]));
+        let two = plan("two", json!([edit("b", "fn z()", "fn z2()")]));
+        let err = conflicts(vec![one, two], &guard);
+        assert!(err.contains("lib.rs: does not apply to the current file: two.json action 001 'b'"), "{}", err);
+        assert!(err.contains("'old' block not found"), "{}", err);
+    }
+
+    #[test]
+    fn treats_patch_renames_as_moves() {
+        let (_dir, guard) = project();
+        let rename = "--- a/lib.rs\n+++ b/main.rs\n@@ -1 +1 @@\n-fn a() {}\n+fn main() {}\n";
+        let one = plan("one", json!([{ "type": "apply_patch", "patch": rename }]));
+        let two = plan("two", json!([edit("b", "fn c()", "fn c2()")]));
+        let err = conflicts(vec![one, two], &guard);
+        assert!(err.contains("lib.rs: one.json action 001 (apply_patch:main.rs) deletes or moves it"), "{}", err);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::validate;
+use anyhow::Result;
+use serde_json::Value;
+
+/// Plan format version written by this build.
+pub const CURRENT_VERSION: u32 = 2;
+
+/// Version of plans without a `version` field, written before the format was versioned.
+pub fn unversioned() -> u32 { 1 }
+
+/// Upgrade steps: `MIGRATIONS[n]` turns a version `n + 1` plan into a version `n + 2` one,
+/// appending a note for every change it makes. Add one here whenever a change to `Plan` or
+/// `Action` would make older plan files invalid, and bump `CURRENT_VERSION`.
+const MIGRATIONS: &[fn(&mut Value, &mut Vec<String>)] = &[v1_to_v2];
+
+/// Version a plan document declares.
+pub fn version_of(plan: &Value) -> Result<u32> {
+    match plan.get("version") {
+        None => Ok(unversioned()),
+        Some(v) => match v.as_u64() {
+            Some(n) if (1..=u32::MAX as u64).contains(&n) => Ok(n as u32),
+            _ => anyhow::bail!("'version' must be a positive integer, found {}", v),
+        },
+    }
+}
+
+/// Bring a plan document up to `CURRENT_VERSION` in place and describe what changed.
+pub fn upgrade(plan: &mut Value) -> Result<Vec<String>> {
+    let from = version_of(plan)?;
+    if from > CURRENT_VERSION {
+        anyhow::bail!(
+            "plan format version {} is newer than this jpc supports ({}); upgrade jpc",
+            from,
+            CURRENT_VERSION
+        );
+    }
+    let mut notes = Vec::new();
+    for step in &MIGRATIONS[from as usize - 1..] {
+        step(plan, &mut notes);
+    }
+    if let Some(obj) = plan.as_object_mut() {
+        obj.insert("version".into(), CURRENT_VERSION.into());
+    }
+    Ok(notes)
+}
+
+/// Version 1 plans were read leniently and unknown fields silently ignored; version 2
+/// rejects them. Drop the fields the current format does not know, so that old plans keep
+/// meaning what they meant. Fields added since are kept: plans without a `version` are read
+/// as version 1, and may well use them.
+fn v1_to_v2(plan: &mut Value, notes: &mut Vec<String>) {
+    let schema = validate::schema();
+    let fields = |s: &Value| -> Vec<String> {
+        s.get("properties").and_then(Value::as_object).map(|p| p.keys().cloned().collect()).unwrap_or_default()
+    };
+    let action = &schema["definitions"]["Action"];
+    let common = fields(action);
+    let kinds: Vec<(String, Vec<String>)> = action["oneOf"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|v| Some((v.pointer("/properties/type/enum/0")?.as_str()?.to_string(), fields(v))))
+        .collect();
+    let plan_fields = fields(&schema);
+    let Some(obj) = plan.as_object_mut() else { return };
+    obj.retain(|k, _| {
+        let keep = plan_fields.contains(k);
+        if !keep { notes.push(format!("removed unknown field $.{}", k)); }
+        keep
+    });
+    let Some(actions) = obj.get_mut("actions").and_then(Value::as_array_mut) else { return };
+    for (i, act) in actions.iter_mut().enumerate() {
+        let ty = act.get("type").and_then(Value::as_str).map(str::to_string);
+        let Some((_, own)) = kinds.iter().find(|(t, _)| Some(t) == ty.as_ref()) else { continue };
+        let Some(a) = act.as_object_mut() else { continue };
+        a.retain(|k, _| {
+            let keep = common.contains(k) || own.contains(k);
+            if !keep { notes.push(format!("removed unknown field $.actions[{}].{}", i, k)); }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn unversioned_plans_keep_every_current_field() {
+        let actions = json!([
+            {
+                "type": "run", "cmd": "cargo", "args": ["test"], "cwd": "crates/a", "env": { "RUST_LOG": "debug" },
+                "clear_env": true, "timeout_secs": 60, "id": "test", "depends_on": ["fmt"], "on_failure": ["clean"],
+                "continue_on_error": true,
+            },
+            { "type": "write_file", "path": "a.rs", "content": "x", "create_dirs": true, "style": { "line_endings": "crlf", "bom": false } },
+            { "type": "insert_text", "path": "a.rs", "text": "y", "after": "x", "regex": true, "occurrence": "last" },
+            { "type": "replace_rust_item", "path": "a.rs", "item": "A", "kind": "impl", "code": "fn a() {}", "add": true },
+            { "type": "apply_patch", "patch": "", "fuzz": 0, "max_offset": 3, "expected_hashes": { "a.rs": "sha256:0" } },
+            { "type": "rename_file", "from": "a", "to": "b", "overwrite": true, "expected_hash": "sha256:0" },
+            { "type": "run", "id": "fmt", "cmd": "cargo", "args": ["fmt"] },
+            { "type": "run", "id": "clean", "cmd": "cargo", "args": ["clean"] },
+        ]);
+        let mut plan = json!({ "description": "d", "params": { "name": { "default": "x" } }, "actions": actions });
+        let notes = upgrade(&mut plan).unwrap();
+        assert!(notes.is_empty(), "{:?}", notes);
+        assert_eq!(plan["version"], CURRENT_VERSION);
+        assert_eq!(plan["actions"], actions);
+        assert_eq!(plan["params"]["name"]["default"], "x");
+    }
+
+    #[test]
+    fn unknown_fields_are_still_removed() {
+        let mut plan = json!({
+            "description": "d",
+            "author": "someone",
+            "actions": [{ "type": "run", "cmd": "ls", "args": [], "why": "look" }, { "type": "teleport", "to": "x" }],
+        });
+        let notes = upgrade(&mut plan).unwrap();
+        assert_eq!(notes, ["removed unknown field $.author", "removed unknown field $.actions[0].why"]);
+        assert_eq!(plan["actions"][0], json!({ "type": "run", "cmd": "ls", "args": [] }));
+        // Unknown action types are left for validation to report.
+        assert_eq!(plan["actions"][1]["to"], "x");
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let mut plan = json!({ "version": CURRENT_VERSION + 1, "description": "d", "actions": [] });
+        assert!(upgrade(&mut plan).unwrap_err().to_string().contains("newer than this jpc supports"));
+        assert!(version_of(&json!({ "version": 0 })).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::Result;
+use regex::Regex;
+use std::sync::LazyLock;
+
+static HUNK_HEADER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^@@ -(\d+)(?:,(\d+))? \+(\d+)(?:,(\d+))? @@").expect("valid regex"));
+
+/// A parsed multi-file unified diff.
+#[derive(Debug, Clone)]
+pub struct Patch {
+    pub files: Vec<FilePatch>,
+}
+
+/// The hunks for one file. A `None` old path means the file is created, a `None` new path
+/// means it is deleted (`/dev/null` in the diff header).
+#[derive(Debug, Clone)]
+pub struct FilePatch {
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub lines: Vec<HunkLine>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+impl FilePatch {
+    /// Path the patch leaves behind: the new path, or the old one for deletions. Renames
+    /// (`--- a/old` / `+++ b/new`) read the old path and remove it.
+    pub fn target(&self) -> &str {
+        self.new_path.as_deref().or(self.old_path.as_deref()).unwrap_or("")
+    }
+}
+
+impl Hunk {
+    fn header(&self) -> String {
+        format!("@@ -{},{} +{},{} @@", self.old_start, self.old_len, self.new_start, self.new_len)
+    }
+
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                HunkLine::Context(s) | HunkLine::Remove(s) => Some(s.as_str()),
+                HunkLine::Add(_) => None,
+            })
+            .collect()
+    }
+
+    fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|l| match l {
+                HunkLine::Context(s) | HunkLine::Add(s) => Some(s.as_str()),
+                HunkLine::Remove(_) => None,
+            })
+            .collect()
+    }
+
+    fn leading_context(&self) -> usize {
+        self.lines.iter().take_while(|l| matches!(l, HunkLine::Context(_))).count()
+    }
+
+    fn trailing_context(&self) -> usize {
+        self.lines.iter().rev().take_while(|l| matches!(l, HunkLine::Context(_))).count()
+    }
+}
+
+pub fn parse(text: &str) -> Result<Patch> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut files: Vec<FilePatch> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        if line.starts_with("--- ") && i + 1 < lines.len() && lines[i + 1].starts_with("+++ ") {
+            files.push(FilePatch {
+                old_path: parse_path(&line[4..]),
+                new_path: parse_path(&lines[i + 1][4..]),
+                hunks: Vec::new(),
+            });
+            i += 2;
+            continue;
+        }
+        if line.starts_with("@@") {
+            let Some(file) = files.last_mut() else {
+                anyhow::bail!("line {}: hunk header before any '---'/'+++' file header", i + 1);
+            };
+            let (old_start, old_len, new_start, new_len) = parse_hunk_header(line)
+                .ok_or_else(|| anyhow::anyhow!("line {}: malformed hunk header '{}'", i + 1, line))?;
+            let mut hunk = Hunk { old_start, old_len, new_start, new_len, lines: Vec::new() };
+            let (mut old_seen, mut new_seen) = (0, 0);
+            i += 1;
+            while i < lines.len() && (old_seen < old_len || new_seen < new_len) {
+                let l = lines[i];
+                match l.chars().next() {
+                    Some(' ') => { hunk.lines.push(HunkLine::Context(l[1..].to_string())); old_seen += 1; new_seen += 1; }
+                    // Some tools strip the single space of empty context lines.
+                    None => { hunk.lines.push(HunkLine::Context(String::new())); old_seen += 1; new_seen += 1; }
+                    Some('-') => { hunk.lines.push(HunkLine::Remove(l[1..].to_string())); old_seen += 1; }
+                    Some('+') => { hunk.lines.push(HunkLine::Add(l[1..].to_string())); new_seen += 1; }
+                    Some('\\') => {}
+                    _ => anyhow::bail!(
+                        "line {}: unexpected line inside {} for {}: '{}'",
+                        i + 1, hunk.header(), file.target(), l
+                    ),
+                }
+                i += 1;
+            }
+            if old_seen != old_len || new_seen != new_len {
+                anyhow::bail!(
+                    "{} for {} is truncated: expected {} old/{} new lines, found {}/{}",
+                    hunk.header(), file.target(), old_len, new_len, old_seen, new_seen
+                );
+            }
+            file.hunks.push(hunk);
+            continue;
+        }
+        i += 1;
+    }
+    if files.is_empty() {
+        anyhow::bail!("patch contains no file headers ('--- a/...' / '+++ b/...')");
+    }
+    Ok(Patch { files })
+}
+
+fn parse_path(raw: &str) -> Option<String> {
+    // Drop an optional trailing timestamp separated by a tab.
+    let p = raw.split('\t').next().unwrap_or("").trim();
+    if p == "/dev/null" { return None; }
+    let p = p.strip_prefix("a/").or_else(|| p.strip_prefix("b/")).unwrap_or(p);
+    Some(p.to_string())
+}
+
+fn parse_hunk_header(line: &str) -> Option<(usize, usize, usize, usize)> {
+    let c = HUNK_HEADER.captures(line)?;
+    let num = |i: usize| c.get(i).map(|m| m.as_str().parse::<usize>().ok()).unwrap_or(Some(1));
+    Some((num(1)?, num(2)?, num(3)?, num(4)?))
+}
+
+/// Apply the hunks of one file to `content`.
+///
+/// Each hunk is first looked up at its expected line (shifted by the drift of the previous
+/// hunks), then up to `max_offset` lines away in both directions (unbounded when `None`).
+/// If that fails, up to `fuzz` leading and trailing context lines are ignored, as `patch -F` does.
+pub fn apply_file(fp: &FilePatch, content: &str, fuzz: usize, max_offset: Option<usize>) -> Result<String> {
+    let trailing_newline = content.is_empty() || content.ends_with('\n');
+    let mut lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
+    let mut drift: isize = 0;
+
+    for (n, h) in fp.hunks.iter().enumerate() {
+        let old = h.old_lines();
+        let new = h.new_lines();
+
+        // Pure insertion: "-N,0" means "after line N".
+        if old.is_empty() {
+            let pos = (h.old_start as isize + drift).clamp(0, lines.len() as isize) as usize;
+            lines.splice(pos..pos, new.iter().map(|s| s.to_string()));
+            drift += new.len() as isize;
+            continue;
+        }
+
+        let max_fuzz = fuzz.min(h.leading_context().max(h.trailing_context()));
+        let mut applied = false;
+        for f in 0..=max_fuzz {
+            let lead = h.leading_context().min(f);
+            let trail = h.trailing_context().min(f);
+            if lead + trail >= old.len() { break; }
+            let old_slice = &old[lead..old.len() - trail];
+            let new_slice = &new[lead..new.len() - trail];
+            let orig = h.old_start as isize - 1 + lead as isize;
+            let expected = (orig + drift).max(0) as usize;
+            if let Some(pos) = find_near(&lines, old_slice, expected, max_offset) {
+                lines.splice(pos..pos + old_slice.len(), new_slice.iter().map(|s| s.to_string()));
+                drift = pos as isize - orig + new_slice.len() as isize - old_slice.len() as isize;
+                applied = true;
+                break;
+            }
+        }
+        if !applied {
+            let expected = (h.old_start as isize - 1 + drift).max(0) as usize;
+            anyhow::bail!(
+                "{}: hunk #{} ({}) failed: {} (searched {} of line {}, fuzz up to {})",
+                fp.target(),
+                n + 1,
+                h.header(),
+                mismatch_reason(&lines, &old, expected),
+                max_offset.map(|m| format!("±{} lines", m)).unwrap_or_else(|| "the whole file".into()),
+                expected + 1,
+                max_fuzz
+            );
+        }
+    }
+
+    let mut out = lines.join("\n");
+    if trailing_newline && !lines.is_empty() { out.push('\n'); }
+    Ok(out)
+}
+
+#[allow(clippy::collapsible_if)]
+fn find_near(lines: &[String], needle: &[&str], expected: usize, max_offset: Option<usize>) -> Option<usize> {
+    if needle.len() > lines.len() { return None; }
+    let last = lines.len() - needle.len();
+    let matches_at = |pos: usize| lines[pos..pos + needle.len()].iter().zip(needle).all(|(a, b)| a == b);
+    let limit = max_offset.unwrap_or(lines.len()).min(lines.len());
+    for d in 0..=limit {
+        if let Some(pos) = expected.checked_add(d) {
+            if pos <= last && matches_at(pos) { return Some(pos); }
+        }
+        if d > 0 {
+            if let Some(pos) = expected.checked_sub(d) {
+                if pos <= last && matches_at(pos) { return Some(pos); }
+            }
+        }
+    }
+    None
+}
+
+fn mismatch_reason(lines: &[String], old: &[&str], expected: usize) -> String {
+    for (k, want) in old.iter().enumerate() {
+        match lines.get(expected + k) {
+            None => return format!("file ends at line {} but hunk expects '{}'", lines.len(), want),
+            Some(got) if got != want => {
+                return format!("line {} is '{}', hunk expects '{}'", expected + k + 1, got, want);
+            }
+            _ => {}
+        }
+    }
+    "context not found".into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "fn a() {}\nfn b() {}\nfn c() {}\nfn d() {}\nfn e() {}\n";
+
+    fn apply(diff: &str, content: &str, fuzz: usize, max_offset: Option<usize>) -> Result<String> {
+        let patch = parse(diff).unwrap();
+        apply_file(&patch.files[0], content, fuzz, max_offset)
+    }
+
+    #[test]
+    fn parses_headers_and_hunks() {
+        let diff = "--- a/src/old.rs\t2024-01-01\n+++ b/src/new.rs\n@@ -1 +1,2 @@\n-x\n+y\n+z\n--- /dev/null\n+++ b/added.rs\n@@ -0,0 +1 @@\n+new\n";
+        let patch = parse(diff).unwrap();
+        assert_eq!(patch.files.len(), 2);
+        assert_eq!(patch.files[0].old_path.as_deref(), Some("src/old.rs"));
+        assert_eq!(patch.files[0].target(), "src/new.rs");
+        let h = &patch.files[0].hunks[0];
+        assert_eq!((h.old_start, h.old_len, h.new_start, h.new_len), (1, 1, 1, 2));
+        assert_eq!(patch.files[1].old_path, None);
+        assert!(parse("@@ -1 +1 @@\n-x\n+y\n").is_err());
+        assert!(parse("--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n-x\n+y\n").unwrap_err().to_string().contains("truncated"));
+    }
+
+    #[test]
+    fn applies_at_the_expected_line() {
+        let diff = "--- a/f\n+++ b/f\n@@ -2,3 +2,3 @@\n fn b() {}\n-fn c() {}\n+fn c() { todo!() }\n fn d() {}\n";
+        assert_eq!(apply(diff, FILE, 0, Some(0)).unwrap(), FILE.replace("fn c() {}", "fn c() { todo!() }"));
+    }
+
+    #[test]
+    fn searches_within_the_offset() {
+        // The hunk says line 1, but its context starts at line 3.
+        let diff = "--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n fn c() {}\n-fn d() {}\n+fn d2() {}\n";
+        assert!(apply(diff, FILE, 0, Some(1)).unwrap_err().to_string().contains("hunk #1"));
+        assert_eq!(apply(diff, FILE, 0, Some(2)).unwrap(), FILE.replace("fn d()", "fn d2()"));
+        assert_eq!(apply(diff, FILE, 0, None).unwrap(), FILE.replace("fn d()", "fn d2()"));
+    }
+
+    #[test]
+    fn fuzz_ignores_outer_context() {
+        let diff = "--- a/f\n+++ b/f\n@@ -2,3 +2,3 @@\n fn B() {}\n-fn c() {}\n+fn C() {}\n fn D() {}\n";
+        assert!(apply(diff, FILE, 0, None).is_err());
+        assert_eq!(apply(diff, FILE, 1, None).unwrap(), FILE.replace("fn c()", "fn C()"));
+    }
+
+    #[test]
+    fn later_hunks_follow_the_drift() {
+        let diff = "--- a/f\n+++ b/f\n@@ -1,1 +1,2 @@\n fn a() {}\n+fn a2() {}\n@@ -4,1 +5,1 @@\n-fn d() {}\n+fn d2() {}\n";
+        assert_eq!(apply(diff, FILE, 0, Some(0)).unwrap(), "fn a() {}\nfn a2() {}\nfn b() {}\nfn c() {}\nfn d2() {}\nfn e() {}\n");
+    }
+
+    #[test]
+    fn creates_files_and_keeps_a_missing_final_newline() {
+        let diff = "--- /dev/null\n+++ b/new.rs\n@@ -0,0 +1,2 @@\n+one\n+two\n";
+        assert_eq!(apply(diff, "", 0, None).unwrap(), "one\ntwo\n");
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-x\n+y\n";
+        assert_eq!(apply(diff, "x", 0, None).unwrap(), "y");
+    }
+}
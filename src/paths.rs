@@ -0,0 +1,176 @@
+use crate::config::Config;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Resolves plan paths against the project root and rejects the ones a plan must not touch:
+/// absolute paths, `..` or symlink escapes out of the root, and protected paths such as `.git/`.
+#[derive(Debug, Clone)]
+pub struct PathGuard {
+    root: PathBuf,
+    protected: Vec<PathBuf>,
+    allow_outside_root: bool,
+}
+
+impl PathGuard {
+    pub fn new(root: &Path, protected: &[String], allow_outside_root: bool) -> Result<Self> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("resolving project root {}", root.display()))?;
+        Ok(PathGuard {
+            root,
+            protected: protected.iter().map(PathBuf::from).collect(),
+            allow_outside_root,
+        })
+    }
+
+    pub fn from_config(cfg: &Config, allow_outside_root: bool) -> Result<Self> {
+        let root = match &cfg.project_root {
+            Some(r) => PathBuf::from(r),
+            None => std::env::current_dir()?,
+        };
+        let protected = cfg.protected_paths.clone().unwrap_or_else(crate::config::default_protected_paths);
+        Self::new(&root, &protected, allow_outside_root)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The protected paths, absolute.
+    pub fn protected(&self) -> Vec<PathBuf> {
+        self.protected.iter().map(|p| self.root.join(p)).collect()
+    }
+
+    /// `path` relative to the project root when it lives below it, for display.
+    pub fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root).unwrap_or(path).to_string_lossy().to_string()
+    }
+
+    /// Resolve a path coming from a plan to an absolute path inside the project root.
+    pub fn resolve(&self, raw: &str) -> Result<PathBuf> {
+        if raw.trim().is_empty() {
+            anyhow::bail!("empty path");
+        }
+        let given = Path::new(raw);
+        if given.is_absolute() && !self.allow_outside_root {
+            anyhow::bail!("refusing absolute path '{}' (use --allow-outside-root)", raw);
+        }
+        let resolved = normalize(&self.root.join(given));
+        if !self.allow_outside_root && !resolved.starts_with(&self.root) {
+            anyhow::bail!("refusing path '{}': it escapes the project root {}", raw, self.root.display());
+        }
+        let real = match real_path(&resolved) {
+            Ok(real) => real,
+            Err(_) if self.allow_outside_root => resolved.clone(),
+            Err(e) => return Err(e.context(format!("resolving '{}'", raw))),
+        };
+        if !self.allow_outside_root && !real.starts_with(&self.root) {
+            anyhow::bail!(
+                "refusing path '{}': it resolves through a symlink to {}, outside the project root",
+                raw,
+                real.display()
+            );
+        }
+        // Both ways: a symlink in the project can lead into `.git`.
+        for path in [&resolved, &real] {
+            let rel = path.strip_prefix(&self.root).unwrap_or(path);
+            if let Some(p) = self.protected.iter().find(|p| rel.starts_with(p) || path.starts_with(p)) {
+                anyhow::bail!("refusing path '{}': '{}' is protected", raw, p.display());
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+/// Lexically resolve `.` and `..` components without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::ParentDir => { out.pop(); }
+            Component::CurDir => {}
+            other => out.push(other.as_os_str()),
+        }
+    }
+    out
+}
+
+/// Where `path` really points: its longest existing ancestor is canonicalized (following
+/// symlinks) and the not-yet-existing remainder appended.
+fn real_path(path: &Path) -> Result<PathBuf> {
+    let mut existing = path;
+    let mut rest: Vec<&std::ffi::OsStr> = Vec::new();
+    // symlink_metadata so that a dangling symlink counts as existing and fails to canonicalize.
+    while fs::symlink_metadata(existing).is_err() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+    let mut real = existing.canonicalize()?;
+    for name in rest.into_iter().rev() {
+        real.push(name);
+    }
+    Ok(real)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project() -> (tempfile::TempDir, PathGuard) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(".git")).unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        let guard = PathGuard::new(dir.path(), &[".git".into(), ".jpc".into()], false).unwrap();
+        (dir, guard)
+    }
+
+    fn refusal(guard: &PathGuard, raw: &str) -> String {
+        format!("{:#}", guard.resolve(raw).unwrap_err())
+    }
+
+    #[test]
+    fn resolves_paths_inside_the_root() {
+        let (_dir, guard) = project();
+        assert_eq!(guard.resolve("src/main.rs").unwrap(), guard.root().join("src/main.rs"));
+        assert_eq!(guard.resolve("./src/../new/file.rs").unwrap(), guard.root().join("new/file.rs"));
+    }
+
+    #[test]
+    fn refuses_escapes() {
+        let (_dir, guard) = project();
+        assert!(refusal(&guard, "").contains("empty path"));
+        assert!(refusal(&guard, "/etc/passwd").contains("absolute path"));
+        assert!(refusal(&guard, "src/../../x").contains("escapes the project root"));
+    }
+
+    #[test]
+    fn refuses_protected_paths() {
+        let (_dir, guard) = project();
+        assert!(refusal(&guard, ".git/config").contains("'.git' is protected"));
+        assert!(refusal(&guard, "src/../.jpc/journal").contains("'.jpc' is protected"));
+        // Only whole components match.
+        assert!(guard.resolve(".gitignore").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks() {
+        let (dir, guard) = project();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("out")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join(".git"), dir.path().join("link")).unwrap();
+        assert!(refusal(&guard, "out/file").contains("resolves through a symlink"));
+        assert!(refusal(&guard, "link/config").contains("'.git' is protected"));
+        assert!(refusal(&guard, "link/hooks/pre-commit").contains("'.git' is protected"));
+
+        let lax = PathGuard::new(dir.path(), &[".git".into()], true).unwrap();
+        assert!(lax.resolve("out/file").is_ok());
+        assert!(format!("{:#}", lax.resolve("link/config").unwrap_err()).contains("'.git' is protected"));
+    }
+}
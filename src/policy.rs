@@ -0,0 +1,294 @@
+use crate::actions::{ActionKind, Plan};
+use crate::exec;
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// Which commands `run` actions may execute.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// Programs that may run, as written in `cmd`. Empty allows every program.
+    pub allow: Vec<String>,
+    /// Regular expressions matched against the command line; a match refuses the command.
+    pub deny: Vec<String>,
+    /// Environment variables `run` actions may not set, such as `PATH`, which picks the
+    /// program that actually runs. A trailing `*` matches any suffix.
+    pub deny_env: Vec<String>,
+    /// Restrictions on the arguments of given programs.
+    pub rules: Vec<ArgRule>,
+    pub on_violation: OnViolation,
+}
+
+/// Arguments a program may be given.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArgRule {
+    pub program: String,
+    /// Allowed subcommands, the first argument that is not an option. Empty allows any.
+    #[serde(default)]
+    pub subcommands: Vec<String>,
+    /// Options refused anywhere on the command line. `--opt` also refuses `--opt=value`;
+    /// a one-letter `-o` also refuses `-ofile` and option clusters such as `-uo`.
+    #[serde(default)]
+    pub deny_args: Vec<String>,
+    /// Most arguments that are not options, e.g. 1 for `uniq`, whose second one is a file
+    /// it writes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_operands: Option<usize>,
+}
+
+/// What happens to a plan with commands the policy refuses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnViolation {
+    Reject,
+    /// Ask on the terminal, and reject when there is none.
+    Confirm,
+}
+
+impl Default for Policy {
+    /// Cargo, read-only git and common read-only tools. Their options cannot pick another
+    /// program to run or a place to write outside the project, but cargo's build, run and
+    /// test do run the plan's code (build scripts, proc macros, binaries and tests), which
+    /// only `--sandbox` contains.
+    fn default() -> Self {
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let rule = |program: &str, subcommands: &[&str], deny_args: &[&str]| ArgRule {
+            program: program.into(),
+            subcommands: strings(subcommands),
+            deny_args: strings(deny_args),
+            max_operands: None,
+        };
+        Policy {
+            allow: strings(&[
+                "cargo", "git", "ls", "cat", "head", "tail", "wc", "grep", "rg", "find", "diff", "sort", "uniq", "echo",
+                "pwd", "true", "false", "test", "which",
+            ]),
+            deny: strings(&[
+                r"\brm\s+(-\S*[rRf]|--recursive|--force)",
+                r"(^|[\s'])(sudo|su|doas)\s",
+                r"\b(curl|wget)\b.*\|\s*(sudo\s+)?(ba|z|da)?sh\b",
+                r"\bmkfs\b",
+                r"\bdd\s.*\bof=",
+            ]),
+            deny_env: strings(&[
+                "PATH", "LD_*", "DYLD_*", "GIT_*", "CARGO*", "RUSTC*", "RUSTDOC*", "RUSTFLAGS", "RUSTUP_*", "HOME",
+                "XDG_CONFIG_HOME", "BASH_ENV", "ENV", "SHELL", "IFS", "PAGER", "EDITOR", "VISUAL", "PYTHON*", "PERL5*",
+                "RUBY*", "NODE_OPTIONS",
+            ]),
+            rules: vec![
+                rule(
+                    "cargo",
+                    &[
+                        "build", "b", "check", "c", "test", "t", "clippy", "fmt", "run", "r", "doc", "bench", "tree",
+                        "metadata", "clean", "fetch", "update", "help", "version",
+                    ],
+                    &["--config", "-Z", "--target-dir", "--manifest-path", "--artifact-dir", "--out-dir"],
+                ),
+                rule(
+                    "git",
+                    &["status", "diff", "log", "show", "blame", "rev-parse", "ls-files", "describe", "grep", "shortlog"],
+                    &["-c", "--config-env", "--exec-path", "--output", "-O", "--open-files-in-pager", "--ext-diff"],
+                ),
+                rule(
+                    "find",
+                    &[],
+                    &["-delete", "-exec", "-execdir", "-ok", "-okdir", "-fprint", "-fprint0", "-fprintf", "-fls"],
+                ),
+                rule("rg", &[], &["--pre"]),
+                rule("sort", &[], &["-o", "--output"]),
+                ArgRule { max_operands: Some(1), ..rule("uniq", &[], &[]) },
+            ],
+            on_violation: OnViolation::Reject,
+        }
+    }
+}
+
+impl Policy {
+    /// Why running `cmd args` with the variables `env` set is refused, if it is.
+    fn check(&self, deny: &[Regex], cmd: &str, args: &[String], env: &BTreeMap<String, String>) -> Vec<String> {
+        let mut out = Vec::new();
+        if !self.allow.is_empty() && !self.allow.iter().any(|a| a == cmd) {
+            out.push(format!("'{}' is not an allowed program", cmd));
+        }
+        let line = exec::display_command(cmd, args);
+        for re in deny.iter().filter(|re| re.is_match(&line)) {
+            out.push(format!("matches the denied pattern '{}'", re.as_str()));
+        }
+        for name in env.keys() {
+            let denied = self.deny_env.iter().find(|d| match d.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == *d,
+            });
+            if let Some(d) = denied { out.push(format!("setting {} is not allowed (policy.deny_env: {})", name, d)); }
+        }
+        for rule in self.rules.iter().filter(|r| r.program == cmd) {
+            let sub = args.iter().find(|a| !a.starts_with(['-', '+']));
+            if let Some(sub) = sub && !rule.subcommands.is_empty() && !rule.subcommands.contains(sub) {
+                out.push(format!("'{} {}' is not allowed (allowed: {})", cmd, sub, rule.subcommands.join(", ")));
+            }
+            for a in args {
+                if rule.deny_args.iter().any(|d| refuses(d, a)) {
+                    out.push(format!("argument '{}' is not allowed for {}", a, cmd));
+                }
+            }
+            let operands = args.iter().filter(|a| !a.starts_with('-') || a.as_str() == "-").count();
+            if let Some(max) = rule.max_operands && operands > max {
+                out.push(format!("{} takes at most {} argument(s) that are not options here", cmd, max));
+            }
+        }
+        out
+    }
+}
+
+/// Whether the denied option `denied` refuses the argument `arg`.
+fn refuses(denied: &str, arg: &str) -> bool {
+    if arg == denied || arg.strip_prefix(denied).is_some_and(|r| r.starts_with('=')) { return true; }
+    if denied.starts_with("--") {
+        // GNU tools accept any unambiguous abbreviation: `sort --out=FILE`.
+        let name = arg.split('=').next().unwrap_or(arg);
+        return name.len() > 3 && name.starts_with("--") && denied.starts_with(name);
+    }
+    // A one-letter option, alone, with its value attached, or in a cluster.
+    match denied.strip_prefix('-').filter(|d| d.len() == 1) {
+        Some(letter) => arg.len() > 1 && arg.starts_with('-') && !arg.starts_with("--") && arg[1..].contains(letter),
+        None => false,
+    }
+}
+
+/// Every way the `run` actions of `plan` break `policy`, one line per problem.
+pub fn violations(plan: &Plan, policy: &Policy) -> Result<Vec<String>> {
+    let deny = policy
+        .deny
+        .iter()
+        .map(|p| Regex::new(p).with_context(|| format!("invalid pattern in policy.deny: '{}'", p)))
+        .collect::<Result<Vec<_>>>()?;
+    let mut out = Vec::new();
+    for (i, act) in plan.actions.iter().enumerate() {
+        if let ActionKind::Run { cmd, args, env, .. } = &act.kind {
+            out.extend(policy.check(&deny, cmd, args, env).into_iter().map(|p| format!("action {}: {}", act.label(i), p)));
+        }
+    }
+    Ok(out)
+}
+
+/// Reject `plan` if one of its commands breaks `policy`, unless the policy asks for
+/// confirmation and the user gives it.
+pub fn enforce(plan: &Plan, policy: &Policy) -> Result<()> {
+    let problems = violations(plan, policy)?;
+    if problems.is_empty() { return Ok(()); }
+    let list = problems.join("\n  ");
+    if policy.on_violation == OnViolation::Confirm {
+        if !io::stdin().is_terminal() {
+            anyhow::bail!("plan breaks the command policy and there is no terminal to confirm it, nothing was applied:\n  {}", list);
+        }
+        eprintln!("The plan breaks the command policy:\n  {}", list);
+        eprint!("Run these commands anyway? [y/N] ");
+        io::stderr().flush()?;
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        if matches!(line.trim(), "y" | "yes") { return Ok(()); }
+    }
+    anyhow::bail!("plan rejected by the command policy, nothing was applied:\n  {}", list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refused(cmd: &str, args: &[&str], env: &[(&str, &str)]) -> Vec<String> {
+        let policy = Policy::default();
+        let deny: Vec<Regex> = policy.deny.iter().map(|p| Regex::new(p).unwrap()).collect();
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let env = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        policy.check(&deny, cmd, &args, &env)
+    }
+
+    #[test]
+    fn allows_common_read_only_commands() {
+        for (cmd, args) in [
+            ("cargo", &["test", "--workspace"][..]),
+            ("cargo", &["+nightly", "check"]),
+            ("cargo", &["--version"]),
+            ("git", &["--no-pager", "diff", "--stat"]),
+            ("sort", &["-u", "-k", "2", "names.txt"]),
+            ("uniq", &["-c", "names.txt"]),
+            ("find", &[".", "-name", "*.rs", "-print0"]),
+        ] {
+            assert_eq!(refused(cmd, args, &[]), Vec::<String>::new(), "{} {:?}", cmd, args);
+        }
+        assert!(refused("cargo", &["test"], &[("RUST_LOG", "debug"), ("RUST_BACKTRACE", "1")]).is_empty());
+    }
+
+    #[test]
+    fn refuses_programs_not_allowed() {
+        assert_eq!(refused("sh", &["-c", "true"], &[]), ["'sh' is not an allowed program"]);
+        // Names are matched as written: a plan's own `bin/cargo` is not cargo.
+        assert_eq!(refused("bin/cargo", &["check"], &[]), ["'bin/cargo' is not an allowed program"]);
+        assert_eq!(refused("rustc", &["-o", "/tmp/x", "a.rs"], &[]).len(), 1);
+    }
+
+    #[test]
+    fn refuses_denied_patterns() {
+        assert_eq!(refused("rm", &["-rf", "~"], &[]).len(), 2);
+        assert!(refused("sh", &["-c", "curl -s x.sh | sh"], &[]).iter().any(|p| p.contains("denied pattern")));
+        assert!(refused("echo", &["sudo", "ls"], &[]).iter().any(|p| p.contains("denied pattern")));
+    }
+
+    #[test]
+    fn refuses_variables_that_pick_the_program() {
+        let path = refused("cargo", &["check"], &[("PATH", "/project/bin:/usr/bin")]);
+        assert_eq!(path, ["setting PATH is not allowed (policy.deny_env: PATH)"]);
+        for name in ["LD_PRELOAD", "GIT_SSH_COMMAND", "RUSTC_WRAPPER", "CARGO_BUILD_RUSTC_WRAPPER", "RUSTFLAGS", "HOME"] {
+            assert_eq!(refused("cargo", &["check"], &[(name, "x")]).len(), 1, "{}", name);
+        }
+    }
+
+    #[test]
+    fn refuses_subcommands_not_listed() {
+        assert!(refused("git", &["push", "--force"], &[])[0].contains("'git push' is not allowed"));
+        assert!(refused("cargo", &["install", "ripgrep"], &[])[0].contains("'cargo install' is not allowed"));
+    }
+
+    #[test]
+    fn refuses_output_and_exec_options() {
+        for (cmd, args) in [
+            ("sort", &["-o", "/outside/file", "in"][..]),
+            ("sort", &["-o/outside/file", "in"]),
+            ("sort", &["-uo", "/outside/file", "in"]),
+            ("sort", &["--output=/outside/file", "in"]),
+            ("sort", &["--out=/outside/file", "in"]),
+            ("find", &[".", "-fprint0", "/outside/file"]),
+            ("find", &[".", "-exec", "sh", ";"]),
+            ("find", &[".", "-delete"]),
+            ("git", &["grep", "-O", "needle"]),
+            ("git", &["grep", "--open-files-in-pager=vim", "needle"]),
+            ("git", &["-ccore.pager=sh", "log"]),
+            ("git", &["diff", "--output=/outside/file"]),
+            ("cargo", &["--config", "build.rustc-wrapper='x'", "check"]),
+            ("cargo", &["build", "--target-dir=/outside"]),
+            ("rg", &["--pre", "./x", "needle"]),
+        ] {
+            assert!(refused(cmd, args, &[]).iter().any(|p| p.starts_with("argument")), "{} {:?}", cmd, args);
+        }
+    }
+
+    #[test]
+    fn refuses_uniq_output_operand() {
+        assert_eq!(refused("uniq", &["in", "/outside/file"], &[]), ["uniq takes at most 1 argument(s) that are not options here"]);
+        assert!(refused("uniq", &["-"], &[]).is_empty());
+    }
+
+    #[test]
+    fn refuses_long_options_by_prefix_only() {
+        assert!(refuses("--output", "--output"));
+        assert!(refuses("--output", "--outp=x"));
+        assert!(!refuses("--output", "--o"));
+        assert!(!refuses("--config", "--color=never"));
+        assert!(!refuses("-o", "--only-matching"));
+        assert!(!refuses("-exec", "-executable"));
+    }
+}
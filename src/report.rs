@@ -0,0 +1,197 @@
+use crate::actions::Plan;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// Not reached yet, or never: the plan was rejected before it started.
+    Pending,
+    Applied,
+    Failed,
+    /// A `run` action stopped because it ran past its timeout.
+    TimedOut,
+    /// Not run because a dependency did not succeed or the plan was aborted.
+    Skipped,
+    /// A failure handler whose actions all succeeded.
+    NotNeeded,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Pending => "pending",
+            Status::Applied => "applied",
+            Status::Failed => "failed",
+            Status::TimedOut => "timed_out",
+            Status::Skipped => "skipped",
+            Status::NotNeeded => "not_needed",
+        }
+    }
+}
+
+/// What happened to one action of an applied plan.
+#[derive(Debug, Clone, Serialize)]
+pub struct ActionReport {
+    /// 1-based position in the plan.
+    pub index: usize,
+    pub id: Option<String>,
+    pub action: String,
+    pub status: Status,
+    /// Why the action failed or was skipped.
+    pub message: Option<String>,
+    pub duration_ms: u64,
+    pub bytes_written: u64,
+    /// Backups taken of the files the action modified first.
+    pub backups: Vec<String>,
+    pub exit_code: Option<i32>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+}
+
+/// Outcome of the checks run after the plan was applied.
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    pub command: String,
+    pub ok: bool,
+    pub exit_code: Option<i32>,
+    /// Why verification could not run or failed before the command, e.g. rustfmt errors.
+    pub error: Option<String>,
+    /// Files rustfmt changed.
+    pub formatted: Vec<String>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Whether the plan was reverted because verification failed.
+    pub reverted: bool,
+}
+
+/// A compiler error, with the actions that modified the file it points at.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    /// 1-based indexes of the actions.
+    pub actions: Vec<usize>,
+}
+
+/// Outcome of `jpc apply`, written as JSON by `--report`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApplyReport {
+    pub description: String,
+    /// Journal id of the apply; `None` unless it succeeded.
+    pub apply_id: Option<String>,
+    pub ok: bool,
+    pub rolled_back: bool,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+    pub actions: Vec<ActionReport>,
+    /// Outcome of the post-apply verification, when it ran.
+    pub verification: Option<VerifyReport>,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+impl ApplyReport {
+    pub fn new(plan: &Plan) -> Self {
+        let actions = plan
+            .actions
+            .iter()
+            .enumerate()
+            .map(|(i, a)| ActionReport {
+                index: i + 1,
+                id: a.id.clone(),
+                action: a.short(),
+                status: Status::Pending,
+                message: None,
+                duration_ms: 0,
+                bytes_written: 0,
+                backups: Vec::new(),
+                exit_code: None,
+                stdout: None,
+                stderr: None,
+            })
+            .collect();
+        ApplyReport {
+            description: plan.description.clone(),
+            apply_id: None,
+            ok: false,
+            rolled_back: false,
+            error: None,
+            duration_ms: 0,
+            actions,
+            verification: None,
+            started: Some(Instant::now()),
+        }
+    }
+
+    /// Record the overall result; actions that never ran are marked skipped.
+    pub fn finish(&mut self, result: &Result<()>) {
+        self.ok = result.is_ok();
+        self.error = result.as_ref().err().map(|e| format!("{:#}", e));
+        self.duration_ms = self.started.map_or(0, |s| s.elapsed().as_millis() as u64);
+        for a in self.actions.iter_mut().filter(|a| a.status == Status::Pending) {
+            a.status = Status::Skipped;
+            a.message.get_or_insert_with(|| "not run".into());
+        }
+    }
+
+    pub fn count(&self, status: Status) -> usize {
+        self.actions.iter().filter(|a| a.status == status).count()
+    }
+
+    /// One row per action, followed by the reason of every failure and skip.
+    pub fn table(&self) -> String {
+        let label = |a: &ActionReport| {
+            let l = match &a.id {
+                Some(id) => format!("'{}' {}", id, a.action),
+                None => a.action.clone(),
+            };
+            if l.chars().count() > 48 { format!("{}...", l.chars().take(45).collect::<String>()) } else { l }
+        };
+        let width = self.actions.iter().map(|a| label(a).chars().count()).max().unwrap_or(0).max(6);
+        let mut out = format!("  #    {:<width$}  {:<10}  {:>8}  {:>8}  exit\n", "action", "status", "time", "bytes");
+        for a in &self.actions {
+            out.push_str(&format!(
+                "  {:03}  {:<width$}  {:<10}  {:>6}ms  {:>8}  {}\n",
+                a.index,
+                label(a),
+                a.status.as_str(),
+                a.duration_ms,
+                a.bytes_written,
+                a.exit_code.map(|c| c.to_string()).unwrap_or_default()
+            ));
+        }
+        for a in &self.actions {
+            if let Some(m) = &a.message {
+                out.push_str(&format!("  {:03} {}: {}\n", a.index, a.status.as_str(), m));
+            }
+        }
+        if let Some(v) = &self.verification {
+            let outcome = match (v.ok, v.reverted) {
+                (true, _) => "passed".to_string(),
+                (false, true) => "failed, plan reverted".to_string(),
+                (false, false) => "failed, changes kept".to_string(),
+            };
+            out.push_str(&format!("  verify: {}: {}\n", v.command, outcome));
+            if !v.formatted.is_empty() {
+                out.push_str(&format!("  rustfmt reformatted {}\n", v.formatted.join(", ")));
+            }
+            if let Some(e) = &v.error {
+                out.push_str(&format!("  {}\n", e));
+            }
+            for d in &v.diagnostics {
+                let by = d.actions.iter().map(|i| format!("{:03}", i)).collect::<Vec<_>>().join(", ");
+                let by = if by.is_empty() { "no action".to_string() } else { format!("action {}", by) };
+                out.push_str(&format!("  {}:{}:{}: {} ({})\n", d.file, d.line, d.column, d.message, by));
+            }
+        }
+        out
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?).with_context(|| format!("writing report {}", path))
+    }
+}
@@ -0,0 +1,405 @@
+use anyhow::{Context, Result};
+use proc_macro2::{LineColumn, TokenStream, TokenTree};
+use quote::ToTokens;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use syn::{ImplItem, Item};
+
+/// Kind of Rust item a `replace_rust_item` action refers to. `method` is a function inside
+/// an impl block, addressed as `Type::name`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RustItemKind {
+    Fn,
+    Struct,
+    Enum,
+    Union,
+    Trait,
+    Impl,
+    Method,
+    Type,
+    Const,
+    Static,
+    Mod,
+}
+
+impl RustItemKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            RustItemKind::Fn => "fn",
+            RustItemKind::Struct => "struct",
+            RustItemKind::Enum => "enum",
+            RustItemKind::Union => "union",
+            RustItemKind::Trait => "trait",
+            RustItemKind::Impl => "impl",
+            RustItemKind::Method => "method",
+            RustItemKind::Type => "type",
+            RustItemKind::Const => "const",
+            RustItemKind::Static => "static",
+            RustItemKind::Mod => "mod",
+        }
+    }
+}
+
+/// An item of the file with its location: `start` includes its attributes and doc comments,
+/// `body` is where the item proper starts.
+struct Found {
+    kind: RustItemKind,
+    /// Module path and name, e.g. `["net", "Client", "connect"]` for a method.
+    path: Vec<String>,
+    /// Last segment of the trait for trait impls.
+    trait_name: Option<String>,
+    start: usize,
+    body: usize,
+    end: usize,
+    line: usize,
+    /// For impl blocks: byte offsets of the braces and the names and ranges of the items.
+    braces: Option<(usize, usize)>,
+    members: Vec<(String, usize, usize)>,
+}
+
+impl Found {
+    fn describe(&self) -> String {
+        let name = match &self.trait_name {
+            Some(t) => format!("{} for {}", t, self.path.join("::")),
+            None => self.path.join("::"),
+        };
+        format!("{} {} (line {})", self.kind.as_str(), name, self.line)
+    }
+}
+
+/// Replace the item named `item` in the Rust source `content` with `code`, or with `add`,
+/// append `code` to the impl block named `item` (`Config` or `Display for Config`).
+///
+/// Items are named by their path of inline modules, then their name; methods by their
+/// type then their name (`Config::load`). Impls of a trait and their methods are only
+/// found when it is named: `Display for Config`, `Display for Config::fmt`. The old
+/// item's attributes and doc comments are kept unless `code` has its own, and `code` is
+/// re-indented to the item's position.
+pub fn replace_item(
+    path: &str,
+    content: &str,
+    item: &str,
+    kind: Option<RustItemKind>,
+    code: &str,
+    add: bool,
+) -> Result<String> {
+    let file = syn::parse_file(content).with_context(|| format!("{}: not valid Rust", path))?;
+    let index = LineIndex::new(content);
+    let mut all = Vec::new();
+    collect(&file.items, &[], &index, &mut all);
+
+    let wanted = parse_target(item);
+    let kind = if add { Some(RustItemKind::Impl) } else { kind };
+    let candidates: Vec<&Found> = all
+        .iter()
+        .filter(|f| f.path == wanted.path && f.trait_name == wanted.trait_name)
+        .filter(|f| kind.is_none_or(|k| f.kind == k))
+        .collect();
+    let found = match candidates.as_slice() {
+        [] => anyhow::bail!(
+            "{}: no {}'{}' found (items in the file: {})",
+            path,
+            kind.map(|k| format!("{} ", k.as_str())).unwrap_or_default(),
+            item,
+            summary(&all)
+        ),
+        [one] => *one,
+        many => anyhow::bail!(
+            "{}: '{}' is ambiguous, it matches {}; set \"kind\"",
+            path,
+            item,
+            many.iter().map(|f| f.describe()).collect::<Vec<_>>().join(", ")
+        ),
+    };
+
+    if add {
+        return add_to_impl(path, content, found, code);
+    }
+    let new_has_attrs = if found.kind == RustItemKind::Method {
+        let parsed: ImplItem = syn::parse_str(code).with_context(|| format!("{}: 'code' is not a valid method", path))?;
+        starts_with_attr(parsed.to_token_stream())
+    } else {
+        let parsed: Item = syn::parse_str(code).with_context(|| format!("{}: 'code' is not a valid Rust item", path))?;
+        starts_with_attr(parsed.to_token_stream())
+    };
+    let start = if new_has_attrs { found.start } else { found.body };
+    let indent = line_indent(content, start);
+    let mut out = String::with_capacity(content.len() + code.len());
+    out.push_str(&content[..start]);
+    out.push_str(&reindent(code, indent));
+    out.push_str(&content[found.end..]);
+    Ok(out)
+}
+
+fn add_to_impl(path: &str, content: &str, imp: &Found, code: &str) -> Result<String> {
+    let parsed: ImplItem = syn::parse_str(code).with_context(|| format!("{}: 'code' is not a valid impl item", path))?;
+    if let Some(name) = impl_item_name(&parsed)
+        && imp.members.iter().any(|(n, _, _)| *n == name)
+    {
+        anyhow::bail!("{}: {} already has an item named '{}'; replace it instead", path, imp.describe(), name);
+    }
+    let (open, close) = imp.braces.expect("impl blocks have braces");
+    let outer = line_indent(content, imp.start);
+    let mut out = String::with_capacity(content.len() + code.len() + 8);
+    match imp.members.last() {
+        Some(&(_, _, last_end)) => {
+            let indent = line_indent(content, imp.members[0].1);
+            out.push_str(&content[..last_end]);
+            out.push_str("\n\n");
+            out.push_str(indent);
+            out.push_str(&reindent(code, indent));
+            out.push_str(&content[last_end..]);
+        }
+        None => {
+            let indent = format!("{}    ", outer);
+            out.push_str(&content[..=open]);
+            out.push('\n');
+            out.push_str(&indent);
+            out.push_str(&reindent(code, &indent));
+            out.push('\n');
+            out.push_str(outer);
+            out.push_str(&content[close..]);
+        }
+    }
+    Ok(out)
+}
+
+struct Target {
+    path: Vec<String>,
+    trait_name: Option<String>,
+}
+
+/// `a::b::name`, or `Trait for a::Type` for a trait impl and `Trait for a::Type::name` for
+/// one of its methods.
+fn parse_target(item: &str) -> Target {
+    let segments = |s: &str| s.split("::").map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect::<Vec<_>>();
+    match item.split_once(" for ") {
+        Some((t, ty)) => Target { path: segments(ty), trait_name: segments(t).pop() },
+        None => Target { path: segments(item), trait_name: None },
+    }
+}
+
+fn collect(items: &[Item], mods: &[String], index: &LineIndex, out: &mut Vec<Found>) {
+    for item in items {
+        let (kind, name) = match item {
+            Item::Fn(i) => (RustItemKind::Fn, i.sig.ident.to_string()),
+            Item::Struct(i) => (RustItemKind::Struct, i.ident.to_string()),
+            Item::Enum(i) => (RustItemKind::Enum, i.ident.to_string()),
+            Item::Union(i) => (RustItemKind::Union, i.ident.to_string()),
+            Item::Trait(i) => (RustItemKind::Trait, i.ident.to_string()),
+            Item::Type(i) => (RustItemKind::Type, i.ident.to_string()),
+            Item::Const(i) => (RustItemKind::Const, i.ident.to_string()),
+            Item::Static(i) => (RustItemKind::Static, i.ident.to_string()),
+            Item::Mod(i) => (RustItemKind::Mod, i.ident.to_string()),
+            Item::Impl(i) => {
+                let syn::Type::Path(tp) = &*i.self_ty else { continue };
+                let Some(ty) = tp.path.segments.last().map(|s| s.ident.to_string()) else { continue };
+                (RustItemKind::Impl, ty)
+            }
+            _ => continue,
+        };
+        let (start, body, end) = index.range(item.to_token_stream());
+        let mut path = mods.to_vec();
+        path.push(name);
+        let mut found = Found {
+            kind,
+            path,
+            trait_name: None,
+            start,
+            body,
+            end,
+            line: index.line_of(start),
+            braces: None,
+            members: Vec::new(),
+        };
+        if let Item::Impl(imp) = item {
+            found.trait_name = imp.trait_.as_ref().and_then(|(_, p, _)| p.segments.last()).map(|s| s.ident.to_string());
+            let span = imp.brace_token.span;
+            found.braces = Some((index.offset(span.open().start()), index.offset(span.close().start())));
+            for member in &imp.items {
+                let (m_start, m_body, m_end) = index.range(member.to_token_stream());
+                let Some(name) = impl_item_name(member) else { continue };
+                found.members.push((name.clone(), m_start, m_end));
+                if let ImplItem::Fn(_) = member {
+                    let mut path = found.path.clone();
+                    path.push(name);
+                    out.push(Found {
+                        kind: RustItemKind::Method,
+                        path,
+                        trait_name: found.trait_name.clone(),
+                        start: m_start,
+                        body: m_body,
+                        end: m_end,
+                        line: index.line_of(m_start),
+                        braces: None,
+                        members: Vec::new(),
+                    });
+                }
+            }
+        }
+        if let Item::Mod(m) = item
+            && let Some((_, inner)) = &m.content
+        {
+            collect(inner, &found.path, index, out);
+        }
+        out.push(found);
+    }
+}
+
+fn impl_item_name(item: &ImplItem) -> Option<String> {
+    match item {
+        ImplItem::Fn(f) => Some(f.sig.ident.to_string()),
+        ImplItem::Const(c) => Some(c.ident.to_string()),
+        ImplItem::Type(t) => Some(t.ident.to_string()),
+        _ => None,
+    }
+}
+
+fn summary(all: &[Found]) -> String {
+    if all.is_empty() { return "none".into(); }
+    let mut names: Vec<String> = all.iter().map(|f| f.describe()).collect();
+    if names.len() > 12 {
+        names.truncate(12);
+        names.push("...".into());
+    }
+    names.join(", ")
+}
+
+/// Outer attributes, doc comments included, come first as `#` followed by a `[...]` group.
+fn starts_with_attr(tokens: TokenStream) -> bool {
+    matches!(tokens.into_iter().next(), Some(TokenTree::Punct(p)) if p.as_char() == '#')
+}
+
+/// The whitespace at the start of the line containing `pos`.
+fn line_indent(content: &str, pos: usize) -> &str {
+    let line_start = content[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let line = &content[line_start..];
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Strip the common indentation of `code` and indent every line but the first with `indent`.
+fn reindent(code: &str, indent: &str) -> String {
+    let code = code.trim_matches('\n').trim_end();
+    let common = code
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start_matches([' ', '\t']).len())
+        .min()
+        .unwrap_or(0);
+    code.lines()
+        .enumerate()
+        .map(|(i, l)| {
+            let l = if l.trim().is_empty() { "" } else { &l[common.min(l.len())..] };
+            if i == 0 || l.is_empty() { l.to_string() } else { format!("{}{}", indent, l) }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Converts the line/column positions of spans into byte offsets.
+struct LineIndex<'a> {
+    text: &'a str,
+    starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(text: &'a str) -> Self {
+        let starts = std::iter::once(0).chain(text.match_indices('\n').map(|(i, _)| i + 1)).collect();
+        LineIndex { text, starts }
+    }
+
+    fn offset(&self, lc: LineColumn) -> usize {
+        let start = self.starts.get(lc.line.saturating_sub(1)).copied().unwrap_or(self.text.len());
+        self.text[start..].char_indices().nth(lc.column).map_or(self.text.len(), |(i, _)| start + i)
+    }
+
+    fn line_of(&self, offset: usize) -> usize {
+        self.starts.partition_point(|&s| s <= offset)
+    }
+
+    /// Start (with attributes), start after the attributes, and end of an item's tokens.
+    fn range(&self, tokens: TokenStream) -> (usize, usize, usize) {
+        let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+        let mut i = 0;
+        while matches!(tokens.get(i), Some(TokenTree::Punct(p)) if p.as_char() == '#')
+            && matches!(tokens.get(i + 1), Some(TokenTree::Group(_)))
+        {
+            i += 2;
+        }
+        let start = tokens.first().map_or(0, |t| self.offset(t.span().start()));
+        let body = tokens.get(i).map_or(start, |t| self.offset(t.span().start()));
+        let end = tokens.last().map_or(start, |t| self.offset(t.span().end()));
+        (start, body, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = r#"use std::fmt;
+
+/// A config.
+#[derive(Debug)]
+pub struct Config {
+    name: String,
+}
+
+impl Config {
+    /// Loads it.
+    pub fn load() -> Self {
+        todo!()
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "config")
+    }
+}
+
+mod net {
+    pub fn load() {}
+}
+"#;
+
+    fn replace(item: &str, kind: Option<RustItemKind>, code: &str) -> Result<String> {
+        replace_item("lib.rs", SRC, item, kind, code, false)
+    }
+
+    #[test]
+    fn keeps_attributes_unless_replaced() {
+        let out = replace("Config", Some(RustItemKind::Struct), "pub struct Config {\n    name: String,\n    port: u16,\n}").unwrap();
+        assert!(out.contains("/// A config.\n#[derive(Debug)]\npub struct Config {\n    name: String,\n    port: u16,\n}\n"));
+        let out = replace("Config", Some(RustItemKind::Struct), "#[derive(Clone)]\npub struct Config;").unwrap();
+        assert!(out.contains("\n#[derive(Clone)]\npub struct Config;\n\nimpl Config"));
+        assert!(!out.contains("A config."));
+    }
+
+    #[test]
+    fn locates_methods_modules_and_trait_impls() {
+        let out = replace("Config::load", None, "pub fn load() -> Self {\n    Config { name: String::new() }\n}").unwrap();
+        assert!(out.contains("    /// Loads it.\n    pub fn load() -> Self {\n        Config { name: String::new() }\n    }\n"));
+        let out = replace("net::load", None, "pub fn load() { connect() }").unwrap();
+        assert!(out.contains("mod net {\n    pub fn load() { connect() }\n}"));
+        let out = replace("Display for Config::fmt", None, "fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {\n    Ok(())\n}").unwrap();
+        assert!(out.contains("        Ok(())\n"));
+        // Trait impls are only found when the trait is named.
+        assert!(replace("Config::fmt", None, "fn fmt() {}").unwrap_err().to_string().contains("no 'Config::fmt' found"));
+    }
+
+    #[test]
+    fn reports_ambiguous_and_missing_items() {
+        let err = replace("Config", Some(RustItemKind::Fn), "fn Config() {}").unwrap_err().to_string();
+        assert!(err.contains("no fn 'Config' found") && err.contains("struct Config (line 3)"), "{}", err);
+        let err = replace("Config", None, "struct Config;").unwrap_err().to_string();
+        assert!(err.contains("ambiguous") && err.contains("impl Config (line 9)"), "{}", err);
+    }
+
+    #[test]
+    fn adds_to_impl_blocks() {
+        let out = replace_item("lib.rs", SRC, "Config", None, "fn name(&self) -> &str {\n    &self.name\n}", true).unwrap();
+        assert!(out.contains("        todo!()\n    }\n\n    fn name(&self) -> &str {\n        &self.name\n    }\n}\n"), "{}", out);
+    }
+}
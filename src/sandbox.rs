@@ -0,0 +1,361 @@
+use crate::paths::PathGuard;
+use anyhow::Result;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+#[cfg(target_os = "linux")]
+use {
+    anyhow::Context,
+    std::ffi::{CStr, CString},
+    std::fs,
+    std::io,
+    std::os::unix::ffi::OsStrExt,
+    std::os::unix::process::CommandExt,
+};
+
+/// What a sandbox leaves writable: the project, except its protected paths.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub project: PathBuf,
+    pub read_only: Vec<PathBuf>,
+}
+
+impl Layout {
+    pub fn new(guard: &PathGuard) -> Layout {
+        Layout { project: guard.root().to_path_buf(), read_only: guard.protected() }
+    }
+}
+
+/// Namespaces a command runs in when sandboxed: its own user, mount and network namespaces,
+/// where the project is the only writable directory besides a private, empty `/tmp`, and
+/// there is no network. Everything is prepared up front, as entering runs in the forked
+/// child, where only system calls are safe.
+#[cfg(target_os = "linux")]
+pub struct Sandbox {
+    project: CString,
+    /// Flags of the mount the project lives on, which its read-only parts must keep.
+    project_flags: libc::c_ulong,
+    /// Protected paths in the project, made read-only.
+    read_only: Vec<CString>,
+    /// Directories created to stand in for missing protected paths, outermost first.
+    placeholders: Vec<PathBuf>,
+    cwd: CString,
+    uid_map: CString,
+    gid_map: CString,
+    /// Every other mount point, with the flags it must keep when made read-only.
+    mounts: Vec<(CString, libc::c_ulong)>,
+    /// Directories leading to the project inside the new `/tmp`, when it lives there.
+    tmp_dirs: Option<Vec<CString>>,
+}
+
+#[cfg(target_os = "linux")]
+impl Sandbox {
+    /// A sandbox laid out as `layout`, for a command started from `cwd`.
+    pub fn new(layout: &Layout, cwd: &Path) -> Result<Sandbox> {
+        let cstr = |p: &Path| CString::new(p.as_os_str().as_bytes()).with_context(|| format!("NUL byte in {}", p.display()));
+        let project = &layout.project;
+        let project = fs::canonicalize(project).with_context(|| format!("resolving {}", project.display()))?;
+        let uid = unsafe { libc::getuid() };
+        let gid = unsafe { libc::getgid() };
+        let info = fs::read_to_string("/proc/self/mountinfo").context("reading /proc/self/mountinfo")?;
+        let mut mounts = Vec::new();
+        let mut project_mount = (0, 0);
+        for line in info.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let (Some(point), Some(options)) = (fields.get(4), fields.get(5)) else { continue };
+            let point = PathBuf::from(unescape(point));
+            let flags = mount_flags(options);
+            if project.starts_with(&point) && point.as_os_str().len() >= project_mount.0 {
+                project_mount = (point.as_os_str().len(), flags);
+            }
+            if point.starts_with(&project) { continue; }
+            mounts.push((cstr(&point)?, flags));
+        }
+        let tmp_dirs = match project.strip_prefix("/tmp") {
+            Ok(rel) => {
+                let dirs: Vec<&Path> = rel.ancestors().filter(|p| !p.as_os_str().is_empty()).collect();
+                Some(dirs.iter().rev().map(|p| cstr(&Path::new("/tmp").join(p))).collect::<Result<_>>()?)
+            }
+            Err(_) => None,
+        };
+        let mut sandbox = Sandbox {
+            project: cstr(&project)?,
+            project_flags: project_mount.1,
+            read_only: Vec::new(),
+            placeholders: Vec::new(),
+            cwd: cstr(cwd)?,
+            uid_map: CString::new(format!("{} {} 1", uid, uid))?,
+            gid_map: CString::new(format!("{} {} 1", gid, gid))?,
+            mounts,
+            tmp_dirs,
+        };
+        for path in &layout.read_only {
+            let Ok(rel) = path.strip_prefix(&layout.project) else { continue };
+            let path = project.join(rel);
+            // A missing protected path gets an empty directory in its place, made read-only
+            // like the others, so commands cannot create it either.
+            if fs::symlink_metadata(&path).is_err() && rel.components().all(|c| matches!(c, Component::Normal(_))) {
+                sandbox.placeholder(&path)?;
+            }
+            let Ok(path) = fs::canonicalize(&path) else { continue };
+            if path.starts_with(&project) { sandbox.read_only.push(cstr(&path)?); }
+        }
+        Ok(sandbox)
+    }
+
+    /// Create `path` and its missing parents, to be removed again once the sandbox is dropped.
+    fn placeholder(&mut self, path: &Path) -> Result<()> {
+        let missing: Vec<&Path> = path.ancestors().take_while(|p| fs::symlink_metadata(p).is_err()).collect();
+        for dir in missing.into_iter().rev() {
+            fs::create_dir(dir).with_context(|| format!("creating a placeholder for {}", path.display()))?;
+            self.placeholders.push(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Make `command` enter the sandbox before it execs.
+    pub fn install(self, command: &mut Command) {
+        // SAFETY: `enter` only makes system calls on data prepared beforehand.
+        unsafe { command.pre_exec(move || self.enter()) };
+    }
+
+    fn enter(&self) -> io::Result<()> {
+        let check = |r: libc::c_int| if r == -1 { Err(io::Error::last_os_error()) } else { Ok(()) };
+        let null = std::ptr::null::<libc::c_char>();
+        unsafe {
+            check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWNET))?;
+            write_file(c"/proc/self/setgroups", b"deny")?;
+            write_file(c"/proc/self/uid_map", self.uid_map.as_bytes())?;
+            write_file(c"/proc/self/gid_map", self.gid_map.as_bytes())?;
+            check(libc::mount(null, c"/".as_ptr(), null, libc::MS_REC | libc::MS_PRIVATE, std::ptr::null()))?;
+            // A mount of its own keeps the project writable once the rest is read-only.
+            let project = self.project.as_ptr();
+            check(libc::mount(project, project, null, libc::MS_BIND | libc::MS_REC, std::ptr::null()))?;
+            for (point, flags) in &self.mounts {
+                let flags = libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | flags;
+                if let Err(e) = check(libc::mount(null, point.as_ptr(), null, flags, std::ptr::null())) {
+                    // Hidden or unreachable mount points cannot be written to anyway.
+                    if !matches!(e.raw_os_error(), Some(libc::ENOENT | libc::EACCES)) { return Err(e); }
+                }
+            }
+            // Keep hold of the project before the new /tmp hides it, if it lives there.
+            let fd = libc::open(project, libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC);
+            check(fd)?;
+            let tmp = libc::MS_NOSUID | libc::MS_NODEV;
+            check(libc::mount(c"tmpfs".as_ptr(), c"/tmp".as_ptr(), c"tmpfs".as_ptr(), tmp, c"mode=1777".as_ptr().cast()))?;
+            if let Some(dirs) = &self.tmp_dirs {
+                for dir in dirs {
+                    check(libc::mkdir(dir.as_ptr(), 0o755))?;
+                }
+                let mut buf = [0u8; 32];
+                check(libc::mount(fd_path(&mut buf, fd).as_ptr(), project, null, libc::MS_BIND | libc::MS_REC, std::ptr::null()))?;
+            }
+            libc::close(fd);
+            // Commands must not plant git hooks or rewrite the journal, which run or get read
+            // outside the sandbox.
+            for path in &self.read_only {
+                let p = path.as_ptr();
+                check(libc::mount(p, p, null, libc::MS_BIND | libc::MS_REC, std::ptr::null()))?;
+                let flags = libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | self.project_flags;
+                check(libc::mount(null, p, null, flags, std::ptr::null()))?;
+            }
+            // The working directory was entered before the mounts changed.
+            check(libc::chdir(self.cwd.as_ptr()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Only the parent drops a sandbox, once the command is done: the child execs or exits.
+#[cfg(target_os = "linux")]
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        for dir in self.placeholders.iter().rev() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+/// Check that commands can run in a sandbox laid out as `layout`, by entering one in a
+/// child that exits right away.
+#[cfg(target_os = "linux")]
+pub fn probe(layout: &Layout) -> Result<()> {
+    let sandbox = Sandbox::new(layout, &layout.project)?;
+    let mut command = Command::new(std::env::current_exe().context("finding the jpc executable")?);
+    // SAFETY: as in `install`; the child exits instead of running anything.
+    unsafe {
+        command.pre_exec(move || {
+            sandbox.enter()?;
+            libc::_exit(0)
+        })
+    };
+    let failed = match command.spawn().and_then(|mut c| c.wait()) {
+        Ok(status) if status.success() => return Ok(()),
+        Ok(status) => format!("the probe failed ({})", status),
+        Err(e) => e.to_string(),
+    };
+    let mut hints = Vec::new();
+    for (file, off, meaning) in [
+        ("/proc/sys/kernel/unprivileged_userns_clone", "0", "unprivileged user namespaces are disabled"),
+        ("/proc/sys/user/max_user_namespaces", "0", "user namespaces are disabled"),
+        ("/proc/sys/kernel/apparmor_restrict_unprivileged_userns", "1", "AppArmor restricts unprivileged user namespaces"),
+    ] {
+        if fs::read_to_string(file).is_ok_and(|v| v.trim() == off) { hints.push(format!("{} ({} is {})", meaning, file, off)); }
+    }
+    if hints.is_empty() { hints.push("the kernel or a container runtime does not permit them".into()); }
+    anyhow::bail!(
+        "cannot create the sandbox (user, mount and network namespaces): {}; {}. Nothing was applied; turn off --sandbox and `sandbox` in the config to run commands unsandboxed",
+        failed,
+        hints.join(", ")
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct Sandbox;
+
+#[cfg(not(target_os = "linux"))]
+impl Sandbox {
+    pub fn new(_layout: &Layout, _cwd: &Path) -> Result<Sandbox> {
+        anyhow::bail!("the sandbox relies on Linux namespaces and is not available on this system")
+    }
+
+    pub fn install(self, _command: &mut Command) {}
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn probe(layout: &Layout) -> Result<()> {
+    Sandbox::new(layout, &layout.project).map(|_| ())
+}
+
+/// Write `data` to the file at `path`, without allocating.
+#[cfg(target_os = "linux")]
+fn write_file(path: &CStr, data: &[u8]) -> io::Result<()> {
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    if fd == -1 { return Err(io::Error::last_os_error()); }
+    let n = unsafe { libc::write(fd, data.as_ptr().cast(), data.len()) };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if n != data.len() as isize { return Err(err); }
+    Ok(())
+}
+
+/// `/proc/self/fd/<fd>` in `buf`, without allocating.
+#[cfg(target_os = "linux")]
+fn fd_path(buf: &mut [u8; 32], fd: libc::c_int) -> &CStr {
+    let prefix = b"/proc/self/fd/";
+    buf[..prefix.len()].copy_from_slice(prefix);
+    let mut digits = [0u8; 10];
+    let (mut n, mut len) = (fd as u32, 0);
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        len += 1;
+        n /= 10;
+        if n == 0 { break; }
+    }
+    for i in 0..len {
+        buf[prefix.len() + i] = digits[len - 1 - i];
+    }
+    buf[prefix.len() + len] = 0;
+    CStr::from_bytes_until_nul(buf).expect("NUL-terminated")
+}
+
+/// Flags of a mountinfo option list that a remount in a user namespace must keep.
+#[cfg(target_os = "linux")]
+fn mount_flags(options: &str) -> libc::c_ulong {
+    options
+        .split(',')
+        .map(|o| match o {
+            "nosuid" => libc::MS_NOSUID,
+            "nodev" => libc::MS_NODEV,
+            "noexec" => libc::MS_NOEXEC,
+            "noatime" => libc::MS_NOATIME,
+            "nodiratime" => libc::MS_NODIRATIME,
+            "relatime" => libc::MS_RELATIME,
+            _ => 0,
+        })
+        .fold(0, |a, b| a | b)
+}
+
+/// A mount point as mountinfo writes it, with spaces and the like as `\040` octal escapes.
+#[cfg(target_os = "linux")]
+fn unescape(s: &str) -> std::ffi::OsString {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        let code = b.get(i + 1..i + 4).and_then(|d| u8::from_str_radix(std::str::from_utf8(d).ok()?, 8).ok());
+        match code {
+            Some(c) if b[i] == b'\\' => {
+                out.push(c);
+                i += 4;
+            }
+            _ => {
+                out.push(b[i]);
+                i += 1;
+            }
+        }
+    }
+    std::ffi::OsStr::from_bytes(&out).to_os_string()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::exec::{self, RunOptions};
+
+    #[test]
+    fn parses_mountinfo_fields() {
+        assert_eq!(unescape(r"/mnt/my\040disk\134x"), std::ffi::OsString::from(r"/mnt/my disk\x"));
+        assert_eq!(unescape(r"/a\0"), std::ffi::OsString::from(r"/a\0"));
+        assert_eq!(mount_flags("rw,nosuid,nodev,relatime"), libc::MS_NOSUID | libc::MS_NODEV | libc::MS_RELATIME);
+        assert_eq!(mount_flags("rw"), 0);
+    }
+
+    #[test]
+    fn formats_fd_paths() {
+        let mut buf = [0u8; 32];
+        assert_eq!(fd_path(&mut buf, 7), c"/proc/self/fd/7");
+        assert_eq!(fd_path(&mut buf, 1234567890), c"/proc/self/fd/1234567890");
+    }
+
+    #[test]
+    fn only_the_project_is_writable() {
+        let project = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::create_dir(project.path().join(".git")).unwrap();
+        let guard = PathGuard::new(project.path(), &[".git".into()], false).unwrap();
+        let layout = Layout::new(&guard);
+        if let Err(e) = probe(&layout) {
+            eprintln!("skipping, no sandbox here: {:#}", e);
+            return;
+        }
+        let script = format!(
+            "echo ok > written && ! (echo x > .git/hook) 2>/dev/null && mkdir -p {0} && echo x > {0}/leak",
+            outside.path().display()
+        );
+        let opts = RunOptions { dir: Some(guard.root().to_path_buf()), sandbox: Some(layout), ..Default::default() };
+        exec::run_cmd("sh", &["-c".into(), script], &opts).unwrap();
+        assert_eq!(fs::read_to_string(project.path().join("written")).unwrap(), "ok\n");
+        assert!(!project.path().join(".git/hook").exists());
+        // The command got a /tmp of its own.
+        assert!(!outside.path().join("leak").exists());
+    }
+
+    #[test]
+    fn missing_protected_paths_cannot_be_created() {
+        let project = tempfile::tempdir().unwrap();
+        let guard = PathGuard::new(project.path(), &[".git".into(), ".jpc/journal".into()], false).unwrap();
+        let layout = Layout::new(&guard);
+        if let Err(e) = probe(&layout) {
+            eprintln!("skipping, no sandbox here: {:#}", e);
+            return;
+        }
+        let script = "! (mkdir -p .git/hooks) 2>/dev/null && ! (echo x > .jpc/journal/entry) 2>/dev/null && echo ok > .jpc/note";
+        let opts = RunOptions { dir: Some(guard.root().to_path_buf()), sandbox: Some(layout), ..Default::default() };
+        exec::run_cmd("sh", &["-c".into(), script.into()], &opts).unwrap();
+        // The placeholders are gone again; what the command wrote next to them is kept.
+        assert!(!project.path().join(".git").exists());
+        assert!(!project.path().join(".jpc/journal").exists());
+        assert_eq!(fs::read_to_string(project.path().join(".jpc/note")).unwrap(), "ok\n");
+    }
+}
@@ -0,0 +1,140 @@
+use crate::actions::Action;
+use anyhow::Result;
+use std::collections::{BTreeSet, HashMap};
+
+/// Index of every action by id.
+pub fn ids(actions: &[Action]) -> HashMap<&str, usize> {
+    let mut ids = HashMap::new();
+    for (i, a) in actions.iter().enumerate() {
+        if let Some(id) = &a.id { ids.entry(id.as_str()).or_insert(i); }
+    }
+    ids
+}
+
+/// Problems with the ids, dependencies and failure handlers of `actions`, as
+/// `(action index, field, message)`.
+pub fn problems(actions: &[Action]) -> Vec<(usize, &'static str, String)> {
+    let mut out = Vec::new();
+    let ids = ids(actions);
+    for (i, a) in actions.iter().enumerate() {
+        if let Some(id) = &a.id {
+            if id.trim().is_empty() {
+                out.push((i, "id", "'id' must not be empty".into()));
+            } else if ids[id.as_str()] != i {
+                out.push((i, "id", format!("duplicate id '{}' (already used by action {:03})", id, ids[id.as_str()] + 1)));
+            }
+        }
+        for (field, refs) in [("depends_on", &a.depends_on), ("on_failure", &a.on_failure)] {
+            for r in refs {
+                match ids.get(r.as_str()) {
+                    None => out.push((i, field, format!("unknown action id '{}'", r))),
+                    Some(&j) if j == i => out.push((i, field, format!("action '{}' refers to itself", r))),
+                    _ => {}
+                }
+            }
+        }
+        if !a.depends_on.is_empty() && actions.iter().any(|o| a.id.as_ref().is_some_and(|id| o.on_failure.contains(id))) {
+            out.push((i, "depends_on", "a failure handler cannot have dependencies; it runs right after the action that failed".into()));
+        }
+    }
+    if out.is_empty() && let Err(stuck) = sort(actions, &ids) {
+        let names: Vec<String> = stuck.iter().map(|j| format!("{:03}", j + 1)).collect();
+        out.push((stuck[0], "depends_on", format!("dependency cycle between actions {}", names.join(", "))));
+    }
+    out
+}
+
+/// Execution order: plan order, except that actions come after their dependencies and
+/// failure handlers after the actions they handle.
+pub fn order(actions: &[Action]) -> Result<Vec<usize>> {
+    let problems = problems(actions);
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "invalid action dependencies, nothing was applied:\n  {}",
+            problems
+                .iter()
+                .map(|(i, field, msg)| format!("action {} {}: {}", actions[*i].label(*i), field, msg))
+                .collect::<Vec<_>>()
+                .join("\n  ")
+        );
+    }
+    Ok(sort(actions, &ids(actions)).unwrap_or_default())
+}
+
+/// Topological sort that always picks the earliest ready action. Returns the actions left
+/// over by a cycle on failure.
+fn sort(actions: &[Action], ids: &HashMap<&str, usize>) -> std::result::Result<Vec<usize>, Vec<usize>> {
+    let n = actions.len();
+    let mut after: Vec<Vec<usize>> = vec![Vec::new(); n];
+    let mut pending = vec![0usize; n];
+    for (i, a) in actions.iter().enumerate() {
+        for d in a.depends_on.iter().filter_map(|d| ids.get(d.as_str())) {
+            after[*d].push(i);
+            pending[i] += 1;
+        }
+        for h in a.on_failure.iter().filter_map(|h| ids.get(h.as_str())) {
+            after[i].push(*h);
+            pending[*h] += 1;
+        }
+    }
+    let mut ready: BTreeSet<usize> = (0..n).filter(|&i| pending[i] == 0).collect();
+    let mut out = Vec::with_capacity(n);
+    while let Some(i) = ready.pop_first() {
+        out.push(i);
+        for &j in &after[i] {
+            pending[j] -= 1;
+            if pending[j] == 0 { ready.insert(j); }
+        }
+    }
+    if out.len() < n {
+        return Err((0..n).filter(|&i| pending[i] > 0).collect());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Actions from `(id, depends_on, on_failure)`.
+    fn actions(spec: &[(&str, &[&str], &[&str])]) -> Vec<Action> {
+        spec.iter()
+            .map(|(id, deps, handlers)| {
+                serde_json::from_value(serde_json::json!({
+                    "type": "create_dir", "path": "x", "id": id, "depends_on": deps, "on_failure": handlers,
+                }))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_plan_order_when_possible() {
+        let acts = actions(&[("a", &[], &[]), ("b", &[], &[]), ("c", &[], &[])]);
+        assert_eq!(order(&acts).unwrap(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn runs_dependencies_and_handlers_first() {
+        let acts = actions(&[("test", &["build"], &[]), ("build", &[], &["clean"]), ("other", &[], &[]), ("clean", &[], &[])]);
+        // Once `build` ran, the earliest ready action is `test`, then the rest in plan order.
+        assert_eq!(order(&acts).unwrap(), [1, 0, 2, 3]);
+    }
+
+    #[test]
+    fn reports_bad_references_and_cycles() {
+        let acts = actions(&[("a", &["b"], &[]), ("b", &["a"], &[])]);
+        let found = problems(&acts);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].2.contains("dependency cycle between actions 001, 002"));
+        assert!(order(&acts).is_err());
+
+        let acts = actions(&[("a", &["a", "nope"], &[]), ("a", &[], &["h"]), ("h", &["a"], &[])]);
+        let found = problems(&acts);
+        let has = |text: &str| found.iter().any(|p| p.2.contains(text));
+        assert!(has("refers to itself"));
+        assert!(has("unknown action id 'nope'"));
+        assert!(has("duplicate id 'a'"));
+        assert!(has("failure handler cannot have dependencies"));
+    }
+}
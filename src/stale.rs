@@ -0,0 +1,197 @@
+use crate::actions::{ActionKind, Plan};
+use crate::config;
+use crate::journal;
+use crate::paths::PathGuard;
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A file whose content on disk no longer matches the hash the plan was made against.
+#[derive(Debug, Clone)]
+pub struct StaleFile {
+    pub action: usize,
+    pub path: String,
+    pub expected: String,
+    pub actual: Option<String>,
+}
+
+impl StaleFile {
+    pub fn describe(&self) -> String {
+        let state = if self.actual.is_some() { "changed on disk" } else { "no longer exists" };
+        format!("action {:03}: {} {} since the plan was made", self.action + 1, self.path, state)
+    }
+}
+
+/// Compare every expected hash of `plan` with the files on disk.
+pub fn check(plan: &Plan, guard: &PathGuard) -> Result<Vec<StaleFile>> {
+    let mut stale = Vec::new();
+    for (i, act) in plan.actions.iter().enumerate() {
+        for (path, expected) in act.expected_hashes() {
+            let actual = journal::hash_file(&guard.resolve(&path)?);
+            if actual.as_deref() != Some(expected.as_str()) {
+                stale.push(StaleFile { action: i, path, expected, actual });
+            }
+        }
+    }
+    Ok(stale)
+}
+
+/// Return the plan to apply given the current state of the tree.
+///
+/// Without `merge`, any stale file rejects the plan. With it, whole-file writes are
+/// three-way merged (base recovered from git `HEAD` or `.jpc/backups` by hash), while edits,
+/// insertions and patches are re-targeted at the current content since they only carry the changed
+/// regions. Deleting or moving a stale file is always refused.
+pub fn resolve(plan: &Plan, guard: &PathGuard, merge: bool) -> Result<Plan> {
+    let stale = check(plan, guard)?;
+    if stale.is_empty() {
+        return Ok(plan.clone());
+    }
+    let listing = stale.iter().map(|s| s.describe()).collect::<Vec<_>>().join("\n  ");
+    if !merge {
+        anyhow::bail!(
+            "plan is stale, nothing was applied (use --merge-stale to attempt a three-way merge):\n  {}",
+            listing
+        );
+    }
+
+    let backups = config::backups_dir().ok();
+    let mut out = plan.clone();
+    let mut conflicts = Vec::new();
+    for s in &stale {
+        let actual = s.actual.clone();
+        match &mut out.actions[s.action].kind {
+            ActionKind::WriteFile { path, content, expected_hash, .. } => {
+                let p = guard.resolve(path)?;
+                let current = fs::read_to_string(&p).unwrap_or_default();
+                let Some(base) = find_base(guard, &p, &s.expected, backups.as_deref()) else {
+                    conflicts.push(format!("{}: original content not found in git HEAD or .jpc/backups", s.path));
+                    continue;
+                };
+                match diffy::merge(&base, content, &current) {
+                    Ok(merged) => {
+                        *content = merged;
+                        *expected_hash = actual;
+                    }
+                    Err(_) => conflicts.push(format!("{}: plan and on-disk changes overlap", s.path)),
+                }
+            }
+            ActionKind::EditFile { expected_hash, .. }
+            | ActionKind::InsertText { expected_hash, .. }
+            | ActionKind::ReplaceRustItem { expected_hash, .. } => *expected_hash = actual,
+            ActionKind::ApplyPatch { expected_hashes, .. } => match actual {
+                Some(h) => { expected_hashes.insert(s.path.clone(), h); }
+                None => conflicts.push(format!("{}: patched file no longer exists", s.path)),
+            },
+            ActionKind::DeleteFile { .. } | ActionKind::RenameFile { .. } => {
+                conflicts.push(format!("{}: refusing to delete or move a file that changed", s.path));
+            }
+            ActionKind::CreateDir { .. } | ActionKind::Run { .. } => {}
+        }
+    }
+    if !conflicts.is_empty() {
+        anyhow::bail!(
+            "cannot merge stale plan, nothing was applied:\n  {}\nstale files:\n  {}",
+            conflicts.join("\n  "),
+            listing
+        );
+    }
+    for s in &stale {
+        eprintln!("merged: {}", s.describe());
+    }
+    Ok(out)
+}
+
+/// Find the content the plan was made against from its hash: the file in git `HEAD`, or
+/// else its most recent backup in `backups` with that hash.
+fn find_base(guard: &PathGuard, path: &Path, hash: &str, backups: Option<&Path>) -> Option<String> {
+    let rel = guard.relative(path);
+    let head = Command::new("git")
+        .arg("-C")
+        .arg(guard.root())
+        .arg("show")
+        .arg(format!("HEAD:{}", rel))
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| o.stdout);
+    if let Some(data) = head.filter(|d| journal::hash_bytes(d) == hash) {
+        return String::from_utf8(data).ok();
+    }
+    // Only the backups of this file are read, newest run first, until one matches.
+    let name = journal::backup_name(path);
+    let mut runs: Vec<PathBuf> = fs::read_dir(backups?).ok()?.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    runs.sort_unstable_by(|a, b| b.cmp(a));
+    runs.iter()
+        .filter_map(|run| fs::read(run.join(&name)).ok())
+        .find(|d| journal::hash_bytes(d) == hash)
+        .and_then(|d| String::from_utf8(d).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A git repository with `a.txt` committed as "one\n", then changed on disk to "two\n".
+    fn repo() -> (tempfile::TempDir, PathGuard) {
+        let dir = tempfile::tempdir().unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git").arg("-C").arg(dir.path()).args(args).output().unwrap().status;
+            assert!(status.success(), "git {:?}", args);
+        };
+        git(&["init", "-q"]);
+        fs::write(dir.path().join("a.txt"), "one\n").unwrap();
+        git(&["add", "a.txt"]);
+        git(&["-c", "user.name=t", "-c", "user.email=t@t", "commit", "-qm", "one"]);
+        fs::write(dir.path().join("a.txt"), "two\n").unwrap();
+        let guard = PathGuard::new(dir.path(), &[], false).unwrap();
+        (dir, guard)
+    }
+
+    fn plan(content: &str, expected: &str) -> Plan {
+        serde_json::from_value(serde_json::json!({
+            "version": 2,
+            "description": "d",
+            "actions": [{ "type": "write_file", "path": "a.txt", "content": content, "expected_hash": expected }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn finds_the_base_in_head_or_the_latest_matching_backup() {
+        let (dir, guard) = repo();
+        let path = guard.resolve("a.txt").unwrap();
+        let backups = dir.path().join("backups");
+        let name = journal::backup_name(&path);
+        for (run, content) in [("20250101000000000", "zero\n"), ("20250102000000000", "two\n"), ("20250103000000000", "three\n")] {
+            let file = backups.join(run).join(&name);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, content).unwrap();
+        }
+        let base = |content: &str| find_base(&guard, &path, &journal::hash_bytes(content.as_bytes()), Some(&backups));
+        // Fresh: the file as committed.
+        assert_eq!(base("one\n").as_deref(), Some("one\n"));
+        // Stale since the commit: from the backups, older ones included.
+        assert_eq!(base("two\n").as_deref(), Some("two\n"));
+        assert_eq!(base("zero\n").as_deref(), Some("zero\n"));
+        // Missing everywhere.
+        assert_eq!(base("never\n"), None);
+        assert_eq!(find_base(&guard, &path, &journal::hash_bytes(b"two\n"), None), None);
+    }
+
+    #[test]
+    fn keeps_fresh_plans_and_rejects_stale_ones_without_merge() {
+        let (_dir, guard) = repo();
+        let fresh = plan("three\n", &journal::hash_bytes(b"two\n"));
+        assert!(check(&fresh, &guard).unwrap().is_empty());
+        assert_eq!(serde_json::to_value(resolve(&fresh, &guard, false).unwrap()).unwrap(), serde_json::to_value(&fresh).unwrap());
+
+        let stale = plan("three\n", &journal::hash_bytes(b"one\n"));
+        let found = check(&stale, &guard).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].describe(), "action 001: a.txt changed on disk since the plan was made");
+        let err = resolve(&stale, &guard, false).unwrap_err().to_string();
+        assert!(err.contains("plan is stale, nothing was applied"), "{}", err);
+    }
+}
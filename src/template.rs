@@ -0,0 +1,154 @@
+use crate::actions::Plan;
+use anyhow::{Context, Result};
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// A variable declared by a reusable plan. Without a default it must be given with `--var`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Param {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// Parse `--var name=value` arguments.
+pub fn parse_vars(raw: &[String]) -> Result<BTreeMap<String, String>> {
+    let mut vars = BTreeMap::new();
+    for r in raw {
+        let (name, value) = r
+            .split_once('=')
+            .with_context(|| format!("invalid --var '{}': expected NAME=VALUE", r))?;
+        vars.insert(name.trim().to_string(), value.to_string());
+    }
+    Ok(vars)
+}
+
+/// Substitute `{{name}}` placeholders of the declared params in every string of the actions
+/// (paths, contents, edits, patches, commands and their arguments).
+///
+/// Placeholders that do not name a declared param are left alone, so that file contents
+/// such as `format!("{{}}")` survive untouched. Every problem with the supplied variables is
+/// reported before anything is substituted.
+pub fn instantiate(plan: &Plan, vars: &BTreeMap<String, String>) -> Result<Plan> {
+    let unknown: Vec<&str> = vars.keys().filter(|k| !plan.params.contains_key(*k)).map(|k| k.as_str()).collect();
+    if !unknown.is_empty() {
+        let declared: Vec<&str> = plan.params.keys().map(|k| k.as_str()).collect();
+        anyhow::bail!(
+            "unknown variable(s) {} (the plan declares: {})",
+            unknown.join(", "),
+            if declared.is_empty() { "none".to_string() } else { declared.join(", ") }
+        );
+    }
+
+    let mut values: BTreeMap<&str, &str> = BTreeMap::new();
+    let mut missing = Vec::new();
+    for (name, param) in &plan.params {
+        match vars.get(name).or(param.default.as_ref()) {
+            Some(v) => { values.insert(name, v); }
+            None => missing.push(match &param.description {
+                Some(d) => format!("{} ({})", name, d),
+                None => name.clone(),
+            }),
+        }
+    }
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "missing value for required variable(s): {}; pass them with --var NAME=VALUE",
+            missing.join(", ")
+        );
+    }
+    if values.is_empty() {
+        return Ok(plan.clone());
+    }
+
+    let re = Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_-]*)\s*\}\}").expect("valid placeholder regex");
+    let mut actions = serde_json::to_value(&plan.actions)?;
+    substitute(&mut actions, &re, &values);
+    Ok(Plan {
+        version: plan.version,
+        description: re.replace_all(&plan.description, |c: &regex::Captures| lookup(c, &values)).to_string(),
+        actions: serde_json::from_value(actions).context("substituted plan is not valid")?,
+        params: BTreeMap::new(),
+    })
+}
+
+fn substitute(v: &mut Value, re: &Regex, values: &BTreeMap<&str, &str>) {
+    match v {
+        Value::String(s) if re.is_match(s) => {
+            *s = re.replace_all(s, |c: &regex::Captures| lookup(c, values)).to_string();
+        }
+        Value::Array(a) => a.iter_mut().for_each(|x| substitute(x, re, values)),
+        Value::Object(o) => o.values_mut().for_each(|x| substitute(x, re, values)),
+        _ => {}
+    }
+}
+
+fn lookup(c: &regex::Captures, values: &BTreeMap<&str, &str>) -> String {
+    match values.get(&c[1]) {
+        Some(v) => v.to_string(),
+        None => c[0].to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn plan() -> Plan {
+        serde_json::from_value(json!({
+            "version": 2,
+            "description": "Add the {{name}} module",
+            "actions": [
+                { "type": "write_file", "path": "src/{{ name }}.rs", "content": "// by {{author}}\nprintln!(\"{{}}\");\n" },
+                { "type": "run", "cmd": "cargo", "args": ["test", "{{name}}", "{{unknown}}"] },
+            ],
+            "params": {
+                "name": { "description": "module name" },
+                "author": { "default": "nobody" },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn vars(raw: &[&str]) -> BTreeMap<String, String> {
+        parse_vars(&raw.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn substitutes_vars_and_defaults() {
+        let got = instantiate(&plan(), &vars(&["name=parser"])).unwrap();
+        assert_eq!(got.description, "Add the parser module");
+        assert!(got.params.is_empty());
+        let actions = serde_json::to_value(&got.actions).unwrap();
+        assert_eq!(actions[0]["path"], "src/parser.rs");
+        // Undeclared placeholders and format strings are left alone.
+        assert_eq!(actions[0]["content"], "// by nobody\nprintln!(\"{{}}\");\n");
+        assert_eq!(actions[1]["args"], json!(["test", "parser", "{{unknown}}"]));
+
+        let got = instantiate(&plan(), &vars(&["name=lexer", "author= Ann = B"])).unwrap();
+        assert_eq!(serde_json::to_value(&got.actions).unwrap()[0]["content"], "// by  Ann = B\nprintln!(\"{{}}\");\n");
+    }
+
+    #[test]
+    fn reports_missing_and_unknown_vars() {
+        let err = instantiate(&plan(), &BTreeMap::new()).unwrap_err().to_string();
+        assert!(err.contains("missing value for required variable(s): name (module name)"), "{}", err);
+        let err = instantiate(&plan(), &vars(&["name=x", "nmae=y"])).unwrap_err().to_string();
+        assert!(err.contains("unknown variable(s) nmae (the plan declares: author, name)"), "{}", err);
+        assert!(parse_vars(&["name".into()]).unwrap_err().to_string().contains("expected NAME=VALUE"));
+    }
+
+    #[test]
+    fn leaves_plans_without_params_alone() {
+        let plain: Plan = serde_json::from_value(json!({ "version": 2, "description": "{{x}}", "actions": [] })).unwrap();
+        assert_eq!(instantiate(&plain, &BTreeMap::new()).unwrap().description, "{{x}}");
+        let err = instantiate(&plain, &vars(&["x=1"])).unwrap_err().to_string();
+        assert!(err.contains("the plan declares: none"), "{}", err);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::actions::Plan;
+use crate::files;
+use crate::journal::{self, Change, JournalEntry};
+use anyhow::{Context, Result};
+use std::fs::{self, Permissions};
+use std::path::{Path, PathBuf};
+
+/// What a plan changed so far, in order, so that it can be undone if a later action fails
+/// and journaled once the whole plan succeeded.
+#[derive(Debug)]
+pub struct Transaction {
+    id: String,
+    /// Where this transaction's backups go: `<backups dir>/<apply id>`.
+    backups: PathBuf,
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+enum Entry {
+    /// A file about to be written, edited or removed; `original` is `None` if it did not
+    /// exist yet, `backup` is where its original content was saved.
+    File { path: PathBuf, original: Option<Vec<u8>>, permissions: Option<Permissions>, backup: Option<PathBuf> },
+    /// A directory created by the plan.
+    Dir { path: PathBuf },
+    /// A directory moved by the plan.
+    Moved { from: PathBuf, to: PathBuf },
+}
+
+impl Transaction {
+    /// A transaction backing files up under `backups`, normally `config::backups_dir()`.
+    pub fn new(backups: &Path) -> Self {
+        let id = journal::new_id();
+        Transaction { backups: backups.join(&id), id, entries: Vec::new() }
+    }
+
+    /// Remember the current content of `path` before it is modified, backing it up under
+    /// `<backups>/<apply id>/<path>`. Only the first call per path matters: that is the
+    /// state a rollback or an undo goes back to.
+    pub fn record_file(&mut self, path: &Path) -> Result<()> {
+        let already = self.entries.iter().any(|e| matches!(e, Entry::File { path: p, .. } if p == path));
+        if already { return Ok(()); }
+        let (original, permissions, backup) = if path.is_file() {
+            let data = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+            let perms = fs::metadata(path).with_context(|| format!("reading {}", path.display()))?.permissions();
+            let bpath = self.backups.join(journal::backup_name(path));
+            if let Some(parent) = bpath.parent() { fs::create_dir_all(parent)?; }
+            // The backup keeps the file's mode so that `jpc undo` can restore it too.
+            files::write_atomic(&bpath, &data, Some(perms.clone()))
+                .with_context(|| format!("writing backup {}", bpath.display()))?;
+            (Some(data), Some(perms), Some(bpath))
+        } else {
+            (None, None, None)
+        };
+        self.entries.push(Entry::File { path: path.to_path_buf(), original, permissions, backup });
+        Ok(())
+    }
+
+    /// Create `dir` and any missing parents, remembering which ones were new.
+    pub fn create_dir_all(&mut self, dir: &Path) -> Result<()> {
+        let mut missing: Vec<PathBuf> = Vec::new();
+        let mut cur = Some(dir);
+        while let Some(d) = cur {
+            if d.as_os_str().is_empty() || d.exists() { break; }
+            missing.push(d.to_path_buf());
+            cur = d.parent();
+        }
+        fs::create_dir_all(dir).with_context(|| format!("creating directory {}", dir.display()))?;
+        for d in missing.into_iter().rev() {
+            self.entries.push(Entry::Dir { path: d });
+        }
+        Ok(())
+    }
+
+    pub fn record_move(&mut self, from: &Path, to: &Path) {
+        self.entries.push(Entry::Moved { from: from.to_path_buf(), to: to.to_path_buf() });
+    }
+
+    /// Position in the change log, to be passed to `backups_since`.
+    pub fn mark(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Backups taken since `mark`.
+    pub fn backups_since(&self, mark: usize) -> Vec<PathBuf> {
+        self.entries[mark..]
+            .iter()
+            .filter_map(|e| match e {
+                Entry::File { backup, .. } => backup.clone(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Files written, created or moved in so far that still exist.
+    pub fn changed_files(&self) -> Vec<PathBuf> {
+        let mut out: Vec<PathBuf> = Vec::new();
+        for e in &self.entries {
+            match e {
+                Entry::File { path, .. } if path.is_file() => out.push(path.clone()),
+                Entry::Moved { to, .. } => out.extend(
+                    walkdir::WalkDir::new(to).into_iter().flatten().filter(|f| f.file_type().is_file()).map(|f| f.into_path()),
+                ),
+                _ => {}
+            }
+        }
+        out.sort();
+        out.dedup();
+        out
+    }
+
+    /// Write the journal entry for a successfully applied plan.
+    pub fn commit(self, plan: &Plan) -> Result<JournalEntry> {
+        let changes = self
+            .entries
+            .into_iter()
+            .map(|e| match e {
+                Entry::File { path, original, backup, .. } => Change::File {
+                    path: journal::relative(&path),
+                    before_hash: original.as_deref().map(journal::hash_bytes),
+                    after_hash: journal::hash_file(&path),
+                    backup: backup.map(|b| b.to_string_lossy().to_string()),
+                },
+                Entry::Dir { path } => Change::Dir { path: journal::relative(&path) },
+                Entry::Moved { from, to } => Change::Moved { from: journal::relative(&from), to: journal::relative(&to) },
+            })
+            .collect();
+        let entry = JournalEntry {
+            id: self.id,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            plan: plan.clone(),
+            changes,
+            undone: false,
+        };
+        journal::save(&entry)?;
+        Ok(entry)
+    }
+
+    /// Undo every recorded change in reverse order and return a line per undone change.
+    /// Rollback keeps going on errors so that as much as possible is restored.
+    pub fn rollback(self) -> Vec<String> {
+        let mut summary = Vec::new();
+        for entry in self.entries.into_iter().rev() {
+            let line = match entry {
+                Entry::File { path, original: Some(data), permissions, .. } => {
+                    let res = path
+                        .parent()
+                        .filter(|p| !p.as_os_str().is_empty())
+                        .map_or(Ok(()), fs::create_dir_all)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|_| files::write_atomic(&path, &data, permissions));
+                    match res {
+                        Ok(()) => format!("restored {}", path.display()),
+                        Err(e) => format!("FAILED to restore {}: {:#}", path.display(), e),
+                    }
+                }
+                Entry::File { path, original: None, .. } => {
+                    if !path.exists() { continue; }
+                    match fs::remove_file(&path) {
+                        Ok(()) => format!("removed {}", path.display()),
+                        Err(e) => format!("FAILED to remove {}: {}", path.display(), e),
+                    }
+                }
+                Entry::Dir { path } => match fs::remove_dir(&path) {
+                    Ok(()) => format!("removed directory {}", path.display()),
+                    Err(e) => format!("left directory {} in place: {}", path.display(), e),
+                },
+                Entry::Moved { from, to } => match fs::rename(&to, &from) {
+                    Ok(()) => format!("moved {} back to {}", to.display(), from.display()),
+                    Err(e) => format!("FAILED to move {} back to {}: {}", to.display(), from.display(), e),
+                },
+            };
+            summary.push(line);
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollback_restores_what_the_plan_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b, new_dir) = (dir.path().join("a.rs"), dir.path().join("b.rs"), dir.path().join("src/gen"));
+        fs::write(&a, "a1").unwrap();
+        fs::write(&b, "b1").unwrap();
+        let mut tx = Transaction::new(&dir.path().join("backups"));
+        // The earlier actions of a plan: an edit, a file created in a new directory, a second
+        // edit of the same file and a moved directory.
+        tx.record_file(&a).unwrap();
+        files::write_atomic(&a, b"a2", None).unwrap();
+        tx.create_dir_all(&new_dir).unwrap();
+        let c = new_dir.join("c.rs");
+        tx.record_file(&c).unwrap();
+        fs::write(&c, "c").unwrap();
+        tx.record_file(&a).unwrap();
+        files::write_atomic(&a, b"a3", None).unwrap();
+        let (from, to) = (dir.path().join("old"), dir.path().join("moved"));
+        fs::create_dir(&from).unwrap();
+        fs::write(from.join("d.rs"), "d").unwrap();
+        fs::rename(&from, &to).unwrap();
+        tx.record_move(&from, &to);
+        // Then one fails halfway through its write.
+        tx.record_file(&b).unwrap();
+        fs::write(&b, "b2 (trunc").unwrap();
+
+        assert_eq!(tx.changed_files(), [a.clone(), b.clone(), to.join("d.rs"), c.clone()]);
+        let backups = tx.backups_since(0);
+        assert_eq!(backups.len(), 2);
+        assert_eq!(fs::read_to_string(&backups[0]).unwrap(), "a1");
+
+        let undone = tx.rollback();
+        assert_eq!(undone.len(), 6, "{:?}", undone);
+        assert_eq!(fs::read_to_string(&a).unwrap(), "a1");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "b1");
+        assert!(!c.exists() && !new_dir.exists() && !dir.path().join("src").exists());
+        assert_eq!(fs::read_to_string(from.join("d.rs")).unwrap(), "d");
+        assert!(!to.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backups_keep_the_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("run.sh");
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&script, Permissions::from_mode(0o751)).unwrap();
+        let mut tx = Transaction::new(&dir.path().join("backups"));
+        tx.record_file(&script).unwrap();
+        let backup = &tx.backups_since(0)[0];
+        assert_eq!(fs::metadata(backup).unwrap().permissions().mode() & 0o777, 0o751);
+        fs::write(&script, "changed").unwrap();
+        fs::set_permissions(&script, Permissions::from_mode(0o644)).unwrap();
+        tx.rollback();
+        assert_eq!(fs::read_to_string(&script).unwrap(), "#!/bin/sh\n");
+        assert_eq!(fs::metadata(&script).unwrap().permissions().mode() & 0o777, 0o751);
+    }
+}
@@ -0,0 +1,578 @@
+use crate::actions::{Action, ActionKind, Plan};
+use crate::edit::Occurrence;
+use crate::format::{self, Format};
+use crate::migrate;
+use crate::patch;
+use crate::rust_edit::RustItemKind;
+use crate::schedule;
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// One problem found in a plan file, located by JSON path and line/column.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Problem {
+    /// `file:line:column: path: message`, the way compilers report errors. Line 0 means the
+    /// position is unknown and is left out.
+    pub fn render(&self, source: &str) -> String {
+        if self.line == 0 {
+            return format!("{}: {}: {}", source, self.path, self.message);
+        }
+        format!("{}:{}:{}: {}: {}", source, self.line, self.column, self.path, self.message)
+    }
+}
+
+/// JSON Schema of plan files, generated from the `Plan`/`Action` types.
+pub fn schema() -> Value {
+    serde_json::to_value(schemars::schema_for!(Plan)).expect("plan schema serializes to JSON")
+}
+
+/// An upgrade a plan in an older format version went through before being checked.
+#[derive(Debug, Clone)]
+pub struct Upgrade {
+    pub from: u32,
+    /// What the upgrade changed.
+    pub notes: Vec<String>,
+}
+
+impl Upgrade {
+    /// Explain that the plan in `source` was upgraded in memory, and what that changed.
+    pub fn note(&self, source: &str) -> String {
+        let mut out = format!(
+            "{} uses plan format version {} and was upgraded on load; run `jpc plan upgrade {}` to rewrite it",
+            source, self.from, source
+        );
+        for n in &self.notes {
+            out.push_str(&format!("\n  {}", n));
+        }
+        out
+    }
+}
+
+/// Check `text` against the plan schema and the semantic rules below, collecting every
+/// problem instead of stopping at the first one like `serde_json` does. Plans in an older
+/// format version are upgraded first. Problems are only located by line in JSON files.
+pub fn validate(text: &str, format: Format) -> (Option<Plan>, Vec<Problem>) {
+    let (plan, problems, _) = validate_upgrading(text, format);
+    (plan, problems)
+}
+
+/// `validate`, also returning the upgrade the plan went through, if it was not current.
+pub fn validate_upgrading(text: &str, format: Format) -> (Option<Plan>, Vec<Problem>, Option<Upgrade>) {
+    let parsed = match format {
+        Format::Json => serde_json::from_str(text).map_err(|e| (format!("invalid JSON: {}", e), e.line(), e.column())),
+        other => format::to_value(text, other).map_err(|e| (format!("invalid {}: {:#}", other.name(), e), 0, 0)),
+    };
+    let mut value: Value = match parsed {
+        Ok(v) => v,
+        Err((message, line, column)) => return (None, vec![Problem { path: "$".into(), message, line, column }], None),
+    };
+    let spans = (format == Format::Json).then(|| Spans::scan(text));
+    let locate = |path: &str| spans.as_ref().map_or((0, 0), |s| s.locate(path));
+    let upgraded = migrate::version_of(&value).and_then(|from| Ok((from, migrate::upgrade(&mut value)?)));
+    let upgrade = match upgraded {
+        Ok((from, notes)) => (from < migrate::CURRENT_VERSION).then_some(Upgrade { from, notes }),
+        Err(e) => {
+            let (line, column) = locate("$.version");
+            return (None, vec![Problem { path: "$.version".into(), message: format!("{:#}", e), line, column }], None);
+        }
+    };
+    let schema = schema();
+    let mut found: Vec<(String, String)> = Vec::new();
+    check_schema(&value, &schema, &schema, "$", &mut found);
+
+    // Semantic rules run on every action that is structurally valid on its own, so that one
+    // bad action does not hide the problems of the others.
+    let actions: Vec<(usize, Action)> = value
+        .get("actions")
+        .and_then(Value::as_array)
+        .map(|arr| {
+            arr.iter()
+                .enumerate()
+                .filter_map(|(i, v)| serde_json::from_value::<Action>(v.clone()).ok().map(|a| (i, a)))
+                .collect()
+        })
+        .unwrap_or_default();
+    check_semantics(&actions, &mut found);
+    // Ids can only be checked once every action is known.
+    let total = value.get("actions").and_then(Value::as_array).map_or(0, Vec::len);
+    if actions.len() == total {
+        let parsed: Vec<Action> = actions.into_iter().map(|(_, a)| a).collect();
+        for (i, field, message) in schedule::problems(&parsed) {
+            found.push((format!("$.actions[{}].{}", i, field), message));
+        }
+    }
+
+    let plan = if found.is_empty() {
+        match serde_json::from_value::<Plan>(value) {
+            Ok(plan) => Some(plan),
+            Err(e) => {
+                found.push(("$".into(), e.to_string()));
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut problems: Vec<Problem> = found
+        .into_iter()
+        .map(|(path, message)| {
+            let (line, column) = locate(&path);
+            Problem { path, message, line, column }
+        })
+        .collect();
+    problems.sort_by_key(|p| (p.line, p.column));
+    (plan, problems, upgrade)
+}
+
+/// Parse a plan in any format, failing with the full list of problems if it is not valid.
+pub fn load_plan(text: &str, source: &str) -> Result<Plan> {
+    let format = Format::detect(source, text);
+    let (plan, problems, upgrade) = validate_upgrading(text, format);
+    match plan {
+        Some(plan) if problems.is_empty() => {
+            if let Some(upgrade) = upgrade {
+                eprintln!("note: {}", upgrade.note(source));
+            }
+            Ok(plan)
+        }
+        _ => anyhow::bail!(
+            "invalid plan ({} problem(s)):\n  {}",
+            problems.len(),
+            problems.iter().map(|p| p.render(source)).collect::<Vec<_>>().join("\n  ")
+        ),
+    }
+}
+
+// Only the subset of JSON Schema that schemars emits for our types is interpreted.
+fn check_schema(value: &Value, schema: &Value, root: &Value, path: &str, out: &mut Vec<(String, String)>) {
+    if let Some(r) = schema.get("$ref").and_then(Value::as_str) {
+        let name = r.trim_start_matches("#/definitions/");
+        if let Some(def) = root.get("definitions").and_then(|d| d.get(name)) {
+            check_schema(value, def, root, path, out);
+        }
+        return;
+    }
+
+    if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+        for s in all {
+            check_schema(value, s, root, path, out);
+        }
+    }
+    if let Some(alts) = schema.get("anyOf").and_then(Value::as_array) {
+        // Untagged enums such as `Occurrence`: fine if any alternative accepts the value.
+        let mut reasons = Vec::new();
+        for alt in alts {
+            let mut found = Vec::new();
+            check_schema(value, alt, root, path, &mut found);
+            if found.is_empty() { return; }
+            reasons.extend(found.into_iter().map(|(_, m)| m));
+        }
+        out.push((path.into(), reasons.join(", or ")));
+        return;
+    }
+
+    if let Some(alts) = schema.get("oneOf").and_then(Value::as_array) {
+        // Our only `oneOf` is the internally tagged `ActionKind` enum, flattened into `Action`:
+        // select the variant by tag and check it together with the common `Action` fields.
+        let Some(obj) = value.as_object() else {
+            out.push((path.into(), format!("expected an object, found {}", kind(value))));
+            return;
+        };
+        let tag_of = |alt: &Value| {
+            alt.pointer("/properties/type/enum/0").and_then(Value::as_str).map(str::to_string)
+        };
+        let known: Vec<String> = alts.iter().filter_map(tag_of).collect();
+        match obj.get("type") {
+            None => out.push((path.into(), format!("missing 'type' (one of: {})", known.join(", ")))),
+            Some(Value::String(t)) => match alts.iter().find(|a| tag_of(a).as_deref() == Some(t.as_str())) {
+                Some(alt) => {
+                    let mut alt = alt.clone();
+                    if let (Some(common), Some(Value::Object(props))) = (schema.get("properties"), alt.get_mut("properties")) {
+                        props.extend(common.as_object().into_iter().flatten().map(|(k, v)| (k.clone(), v.clone())));
+                    }
+                    check_schema(value, &alt, root, path, out)
+                }
+                None => out.push((
+                    format!("{}.type", path),
+                    format!("unknown action type '{}' (one of: {})", t, known.join(", ")),
+                )),
+            },
+            Some(other) => out.push((format!("{}.type", path), format!("expected a string, found {}", kind(other)))),
+        }
+        return;
+    }
+
+    if let Some(ty) = schema.get("type") {
+        let allowed: Vec<&str> = match ty {
+            Value::String(s) => vec![s.as_str()],
+            Value::Array(a) => a.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|t| has_type(value, t)) {
+            out.push((path.into(), format!("expected {}, found {}", allowed.join(" or "), kind(value))));
+            return;
+        }
+    }
+
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) && !values.contains(value) {
+        out.push((path.into(), format!("must be one of {}", Value::Array(values.clone()))));
+    }
+    if let (Some(min), Some(n)) = (schema.get("minimum").and_then(Value::as_f64), value.as_f64()) && n < min {
+        out.push((path.into(), format!("must be at least {}", min)));
+    }
+
+    if let Some(obj) = value.as_object() {
+        let props = schema.get("properties").and_then(Value::as_object);
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for r in required.iter().filter_map(Value::as_str) {
+                if !obj.contains_key(r) {
+                    out.push((path.into(), format!("missing required field '{}'", r)));
+                }
+            }
+        }
+        for (k, v) in obj {
+            let child = format!("{}.{}", path, k);
+            match (props.and_then(|p| p.get(k)), schema.get("additionalProperties")) {
+                (Some(s), _) => check_schema(v, s, root, &child, out),
+                (None, Some(Value::Bool(false))) => out.push((child, format!("unknown field '{}'", k))),
+                (None, Some(s @ Value::Object(_))) => check_schema(v, s, root, &child, out),
+                (None, _) => {}
+            }
+        }
+    }
+
+    if let (Some(items), Some(arr)) = (schema.get("items"), value.as_array()) {
+        for (i, v) in arr.iter().enumerate() {
+            check_schema(v, items, root, &format!("{}[{}]", path, i), out);
+        }
+    }
+}
+
+fn has_type(value: &Value, ty: &str) -> bool {
+    match ty {
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Rules the schema cannot express: empty paths, duplicate whole-file writes, malformed
+/// patches and commands that only make sense to a shell. Ids and dependencies are checked
+/// by `schedule::problems`.
+fn check_semantics(actions: &[(usize, Action)], out: &mut Vec<(String, String)>) {
+    let mut writes: HashMap<String, usize> = HashMap::new();
+    for &(i, ref act) in actions {
+        let at = |field: &str| format!("$.actions[{}].{}", i, field);
+        let non_empty = |field: &str, value: &str, out: &mut Vec<(String, String)>| {
+            if value.trim().is_empty() {
+                out.push((at(field), format!("'{}' must not be empty", field)));
+            }
+        };
+        let writes_text = !matches!(
+            act.kind,
+            ActionKind::DeleteFile { .. } | ActionKind::RenameFile { .. } | ActionKind::CreateDir { .. } | ActionKind::Run { .. }
+        );
+        if !writes_text && !act.style.is_keep() {
+            out.push((at("style"), "'style' only applies to actions that write file content".into()));
+        }
+        match &act.kind {
+            ActionKind::WriteFile { path, .. } => {
+                non_empty("path", path, out);
+                let key = path.trim_start_matches("./").to_string();
+                if let Some(first) = writes.insert(key, i) {
+                    out.push((at("path"), format!("duplicate write to '{}' (already written by $.actions[{}])", path, first)));
+                }
+            }
+            ActionKind::EditFile { path, edits, .. } => {
+                non_empty("path", path, out);
+                if edits.is_empty() {
+                    out.push((at("edits"), "'edits' must contain at least one block".into()));
+                }
+                for (j, e) in edits.iter().enumerate() {
+                    if e.old.is_empty() {
+                        out.push((format!("$.actions[{}].edits[{}].old", i, j), "'old' must not be empty".into()));
+                    }
+                }
+            }
+            ActionKind::InsertText { path, text, before, after, regex, occurrence, .. } => {
+                non_empty("path", path, out);
+                if text.is_empty() {
+                    out.push((at("text"), "'text' must not be empty".into()));
+                }
+                match (before, after) {
+                    (Some(_), Some(_)) => out.push((at("after"), "give only one of 'before' and 'after'".into())),
+                    (None, None) => out.push((at("type"), "missing anchor: give 'before' or 'after'".into())),
+                    (Some(a), None) | (None, Some(a)) => {
+                        let field = if before.is_some() { "before" } else { "after" };
+                        if a.is_empty() {
+                            out.push((at(field), format!("'{}' must not be empty", field)));
+                        } else if *regex && let Err(e) = regex::Regex::new(a) {
+                            out.push((at(field), format!("invalid regex: {}", e)));
+                        }
+                    }
+                }
+                if *occurrence == Occurrence::Nth(0) {
+                    out.push((at("occurrence"), "occurrences count from 1".into()));
+                }
+            }
+            ActionKind::ReplaceRustItem { path, item, kind, code, add, .. } => {
+                non_empty("path", path, out);
+                non_empty("item", item, out);
+                if *add && kind.is_some_and(|k| k != RustItemKind::Impl) {
+                    out.push((at("kind"), "with 'add', 'item' names an impl block; 'kind' must be \"impl\" or omitted".into()));
+                }
+                // Without `kind`, `item` may still name a method, so accept impl items too.
+                let in_impl = syn::parse_str::<syn::ImplItem>(code);
+                let parsed = match (*add || *kind == Some(RustItemKind::Method), in_impl) {
+                    (true, r) | (false, r @ Ok(_)) => r.map(|_| ()),
+                    (false, Err(_)) => syn::parse_str::<syn::Item>(code).map(|_| ()),
+                };
+                if let Err(e) = parsed {
+                    out.push((at("code"), format!("not a valid Rust item: {}", e)));
+                }
+            }
+            ActionKind::ApplyPatch { patch: text, .. } => {
+                if let Err(e) = patch::parse(text) {
+                    out.push((at("patch"), format!("invalid unified diff: {:#}", e)));
+                }
+            }
+            ActionKind::DeleteFile { path, .. } | ActionKind::CreateDir { path } => non_empty("path", path, out),
+            ActionKind::RenameFile { from, to, .. } => {
+                non_empty("from", from, out);
+                non_empty("to", to, out);
+            }
+            ActionKind::Run { cmd, args, cwd, env, timeout_secs, .. } => {
+                if cmd.trim().is_empty() {
+                    out.push((at("cmd"), "'cmd' must not be empty".into()));
+                } else if cmd.chars().any(char::is_whitespace) {
+                    out.push((at("cmd"), format!("'{}' looks like a command line; put the program in 'cmd' and each argument in 'args'", cmd)));
+                }
+                for (j, a) in args.iter().enumerate() {
+                    if ["&&", "||", "|", ";", ">", ">>", "<"].contains(&a.as_str()) {
+                        out.push((
+                            format!("$.actions[{}].args[{}]", i, j),
+                            format!("'{}' is a shell operator; commands are not run through a shell", a),
+                        ));
+                    }
+                }
+                if let Some(dir) = cwd { non_empty("cwd", dir, out); }
+                for name in env.keys().filter(|k| k.is_empty() || k.contains(['=', '\0'])) {
+                    out.push((at("env"), format!("'{}' is not a valid environment variable name", name)));
+                }
+                if *timeout_secs == Some(0) {
+                    out.push((at("timeout_secs"), "'timeout_secs' must be at least 1; leave it out to use the configured default".into()));
+                }
+            }
+        }
+    }
+}
+
+/// Start position (1-based line and column) of every value and object key, by JSON path.
+struct Spans {
+    map: HashMap<String, (usize, usize)>,
+}
+
+impl Spans {
+    fn scan(text: &str) -> Self {
+        let mut sc = Scanner { s: text.as_bytes(), i: 0, line: 1, col: 1, map: HashMap::new() };
+        sc.value("$".into());
+        Spans { map: sc.map }
+    }
+
+    /// Position of `path`, or of its closest located ancestor.
+    fn locate(&self, path: &str) -> (usize, usize) {
+        let mut p = path;
+        loop {
+            if let Some(pos) = self.map.get(p) { return *pos; }
+            match p.rfind(['.', '[']) {
+                Some(idx) => p = &p[..idx],
+                None => return (1, 1),
+            }
+        }
+    }
+}
+
+struct Scanner<'a> {
+    s: &'a [u8],
+    i: usize,
+    line: usize,
+    col: usize,
+    map: HashMap<String, (usize, usize)>,
+}
+
+impl Scanner<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.i).copied()
+    }
+
+    fn bump(&mut self) {
+        if let Some(b) = self.peek() {
+            if b == b'\n' {
+                self.line += 1;
+                self.col = 1;
+            } else if b & 0xC0 != 0x80 {
+                // Count characters, not UTF-8 continuation bytes.
+                self.col += 1;
+            }
+            self.i += 1;
+        }
+    }
+
+    fn ws(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) { self.bump(); }
+    }
+
+    fn value(&mut self, path: String) {
+        self.ws();
+        self.map.entry(path.clone()).or_insert((self.line, self.col));
+        match self.peek() {
+            Some(b'{') => {
+                self.bump();
+                loop {
+                    self.ws();
+                    match self.peek() {
+                        Some(b'"') => {
+                            let pos = (self.line, self.col);
+                            let key = self.string();
+                            let child = format!("{}.{}", path, key);
+                            self.map.insert(child.clone(), pos);
+                            self.ws();
+                            if self.peek() == Some(b':') { self.bump(); }
+                            self.value(child);
+                        }
+                        Some(b',') => self.bump(),
+                        Some(b'}') => { self.bump(); return; }
+                        _ => return,
+                    }
+                }
+            }
+            Some(b'[') => {
+                self.bump();
+                let mut n = 0;
+                loop {
+                    self.ws();
+                    match self.peek() {
+                        Some(b',') => self.bump(),
+                        Some(b']') => { self.bump(); return; }
+                        None => return,
+                        _ => {
+                            self.value(format!("{}[{}]", path, n));
+                            n += 1;
+                        }
+                    }
+                }
+            }
+            Some(b'"') => { self.string(); }
+            _ => {
+                while !matches!(self.peek(), None | Some(b',' | b'}' | b']' | b' ' | b'\t' | b'\n' | b'\r')) { self.bump(); }
+            }
+        }
+    }
+
+    /// Consume a string literal and return its raw (still escaped) content.
+    fn string(&mut self) -> String {
+        self.bump();
+        let start = self.i;
+        while let Some(b) = self.peek() {
+            match b {
+                b'\\' => { self.bump(); self.bump(); }
+                b'"' => break,
+                _ => self.bump(),
+            }
+        }
+        let raw = String::from_utf8_lossy(&self.s[start..self.i]).to_string();
+        self.bump();
+        raw
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_locate_keys_values_and_array_items() {
+        let text = "{\n  \"description\": \"d\",\n  \"actions\": [\n    { \"type\": \"run\", \"cmd\": \"ls\" },\n    {\"type\": \"delete_file\", \"path\": \"é\\\"x\"}\n  ]\n}";
+        let spans = Spans::scan(text);
+        assert_eq!(spans.locate("$"), (1, 1));
+        assert_eq!(spans.locate("$.description"), (2, 3));
+        assert_eq!(spans.locate("$.actions[0]"), (4, 5));
+        assert_eq!(spans.locate("$.actions[0].cmd"), (4, 22));
+        assert_eq!(spans.locate("$.actions[1].path"), (5, 29));
+        // Unknown paths fall back to their closest located ancestor.
+        assert_eq!(spans.locate("$.actions[1].path.more"), (5, 29));
+        assert_eq!(spans.locate("$.actions[7].cmd"), (3, 3));
+    }
+
+    #[test]
+    fn collects_every_problem_in_order() {
+        let text = r#"{
+  "version": 2,
+  "description": "d",
+  "actions": [
+    { "type": "write_file", "path": "", "content": "x" },
+    { "type": "create_dir", "path": "x", "colour": 1 },
+    { "type": "run", "cmd": "cargo check", "args": ["&&"] },
+    { "type": "teleport" }
+  ]
+}"#;
+        let (plan, problems) = validate(text, Format::Json);
+        assert!(plan.is_none());
+        let found: Vec<(usize, &str)> = problems.iter().map(|p| (p.line, p.path.as_str())).collect();
+        assert_eq!(
+            found,
+            [
+                (5, "$.actions[0].path"),
+                (6, "$.actions[1].colour"),
+                (7, "$.actions[2].cmd"),
+                (7, "$.actions[2].args[0]"),
+                (8, "$.actions[3].type"),
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_a_valid_plan() {
+        let text = r#"{"version": 2, "description": "d", "actions": [{"type": "create_dir", "path": "src/x"}]}"#;
+        let (plan, problems) = validate(text, Format::Json);
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(plan.unwrap().actions.len(), 1);
+    }
+
+    #[test]
+    fn reports_the_upgrade_of_older_plans() {
+        let text = r#"{"description": "d", "extra": 1, "actions": []}"#;
+        let (plan, problems, upgrade) = validate_upgrading(text, Format::Json);
+        assert!(problems.is_empty() && plan.is_some());
+        let upgrade = upgrade.unwrap();
+        assert_eq!(upgrade.from, 1);
+        assert_eq!(upgrade.notes, ["removed unknown field $.extra"]);
+        let current = r#"{"version": 2, "description": "d", "actions": []}"#;
+        assert!(validate_upgrading(current, Format::Json).2.is_none());
+    }
+}
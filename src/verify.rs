@@ -0,0 +1,261 @@
+use crate::actions::{ApplyOptions, Plan};
+use crate::config::{self, Config};
+use crate::exec;
+use crate::files;
+use crate::paths::PathGuard;
+use crate::report::{ApplyReport, Diagnostic, VerifyReport};
+use crate::sandbox::Layout;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Checks run on the project once a plan applied, before it is committed to the journal.
+#[derive(Debug, Clone)]
+pub struct Verify {
+    pub rustfmt: bool,
+    /// Program and arguments, run from the project root.
+    pub command: Vec<String>,
+    /// Keep the changes when verification fails instead of reverting them.
+    pub keep_on_failure: bool,
+    pub timeout: Option<Duration>,
+}
+
+impl Verify {
+    /// The verification configured for the project rooted at `root`, or `None` if it is off.
+    pub fn from_config(cfg: &Config, root: &Path, keep_on_failure: bool) -> Option<Verify> {
+        if !cfg.verify.unwrap_or_else(|| root.join("Cargo.toml").is_file()) { return None; }
+        let command = cfg.verify_command.clone().filter(|c| !c.is_empty()).unwrap_or_else(config::default_verify_command);
+        Some(Verify {
+            rustfmt: cfg.verify_rustfmt.unwrap_or(true),
+            command,
+            keep_on_failure,
+            timeout: config::run_timeout(cfg),
+        })
+    }
+}
+
+/// Format the `.rs` files in `changed`, run the verify command and record the outcome in
+/// `report`, blaming each compiler error on the actions that modified its file. Returns
+/// whether verification passed.
+pub fn run(verify: &Verify, changed: &[PathBuf], plan: &Plan, opts: &ApplyOptions, report: &mut ApplyReport) -> bool {
+    let guard = &opts.guard;
+    // `cargo check` runs the project's build scripts and proc macros, which the plan may
+    // have written.
+    let sandbox = opts.sandbox.then(|| Layout::new(guard));
+    let (cmd, args) = verify.command.split_first().expect("verify command is not empty");
+    let mut out = VerifyReport {
+        command: exec::display_command(cmd, args),
+        ok: false,
+        exit_code: None,
+        error: None,
+        formatted: Vec::new(),
+        diagnostics: Vec::new(),
+        reverted: false,
+    };
+    let root = guard.root();
+    let rust_files: Vec<&PathBuf> = changed.iter().filter(|p| p.extension().is_some_and(|e| e == "rs")).collect();
+    let mut output = String::new();
+    if verify.rustfmt && !rust_files.is_empty() {
+        match rustfmt(root, &rust_files, sandbox.as_ref()) {
+            Ok(formatted) => out.formatted = formatted.iter().map(|p| guard.relative(p)).collect(),
+            Err(text) => {
+                out.error = Some("rustfmt failed; the verify command was not run".into());
+                output = text;
+            }
+        }
+    }
+    if out.error.is_none() {
+        eprintln!("Verifying: {}", out.command);
+        let opts = exec::RunOptions {
+            dir: Some(root.to_path_buf()),
+            timeout: verify.timeout,
+            sandbox: sandbox.clone(),
+            ..Default::default()
+        };
+        match exec::capture(cmd, args, &opts) {
+            Ok(res) => {
+                out.ok = res.status.success();
+                out.exit_code = res.status.code();
+                if res.timed_out.is_some() || res.interrupted {
+                    out.error = res.check(cmd).err().map(|e| format!("{:#}", e));
+                }
+                output = res.output;
+            }
+            Err(e) => out.error = Some(format!("{:#}", e)),
+        }
+    }
+
+    let touched: HashMap<PathBuf, Vec<usize>> = touched_by(plan, guard);
+    out.diagnostics = errors(&output, root);
+    for d in &mut out.diagnostics {
+        d.actions = touched.get(&root.join(&d.file)).cloned().unwrap_or_default();
+        if let Ok(rel) = Path::new(&d.file).strip_prefix(root) { d.file = rel.to_string_lossy().to_string(); }
+    }
+    for a in &mut report.actions {
+        let n = out.diagnostics.iter().filter(|d| d.actions.contains(&a.index)).count();
+        if n > 0 {
+            a.message = Some(format!("verification reported {} error(s) in files this action modified", n));
+        }
+    }
+    let ok = out.ok;
+    report.verification = Some(out);
+    ok
+}
+
+/// Run rustfmt on each of `files` and return the ones it changed, or its output if it failed.
+/// Sources go through stdin: given a path, rustfmt would also rewrite the out-of-line modules
+/// the file declares, which the plan may not have touched.
+fn rustfmt(root: &Path, files: &[&PathBuf], sandbox: Option<&Layout>) -> Result<Vec<PathBuf>, String> {
+    if exec::which("rustfmt").is_none() {
+        eprintln!("warning: rustfmt not found, skipping formatting");
+        return Ok(Vec::new());
+    }
+    let args = vec!["--edition".to_string(), edition(root), "--emit".into(), "stdout".into()];
+    let mut formatted = Vec::new();
+    for p in files {
+        let Ok(before) = fs::read(p) else { continue };
+        let opts = exec::RunOptions {
+            dir: Some(root.to_path_buf()),
+            input: Some(before.clone()),
+            sandbox: sandbox.cloned(),
+            ..Default::default()
+        };
+        let res = exec::capture("rustfmt", &args, &opts).map_err(|e| format!("{:#}", e))?;
+        if !res.status.success() { return Err(res.output.replace("<stdin>", &p.to_string_lossy())); }
+        if res.stdout.as_bytes() != before {
+            files::write_atomic(p, res.stdout.as_bytes(), None).map_err(|e| format!("{:#}", e))?;
+            formatted.push(p.to_path_buf());
+        }
+    }
+    Ok(formatted)
+}
+
+/// Edition of the package at `root`, which rustfmt does not read from `Cargo.toml` itself.
+fn edition(root: &Path) -> String {
+    fs::read_to_string(root.join("Cargo.toml"))
+        .ok()
+        .and_then(|t| t.parse::<toml::Table>().ok())
+        .and_then(|t| t.get("package")?.get("edition")?.as_str().map(str::to_string))
+        .unwrap_or_else(|| "2021".into())
+}
+
+/// The actions that modify each file, by absolute path.
+fn touched_by(plan: &Plan, guard: &PathGuard) -> HashMap<PathBuf, Vec<usize>> {
+    let mut out: HashMap<PathBuf, Vec<usize>> = HashMap::new();
+    for (i, act) in plan.actions.iter().enumerate() {
+        for p in act.paths().iter().filter_map(|p| guard.resolve(p).ok()) {
+            out.entry(p).or_default().push(i + 1);
+        }
+    }
+    out
+}
+
+/// Errors in compiler output, in the short (`file:line:col: error: msg`) or the human
+/// (`error: msg` then `--> file:line:col`) format. Warnings are left out.
+fn errors(output: &str, root: &Path) -> Vec<Diagnostic> {
+    let short = Regex::new(r"^(\S[^:]*\.rs):(\d+):(\d+): (error.*)$").expect("valid regex");
+    let arrow = Regex::new(r"^\s*--> (\S[^:]*\.rs):(\d+):(\d+)").expect("valid regex");
+    let mut out: Vec<Diagnostic> = Vec::new();
+    let mut header: Option<&str> = None;
+    for line in output.lines() {
+        let (caps, message) = if let Some(c) = short.captures(line) {
+            let m = c[4].to_string();
+            (c, m)
+        } else if let Some(c) = arrow.captures(line) {
+            match header.take() {
+                Some(h) => (c, h.to_string()),
+                None => continue,
+            }
+        } else {
+            if line.starts_with("error") && !line.starts_with("error: could not compile") {
+                header = Some(line);
+            } else if line.starts_with("warning") {
+                header = None;
+            }
+            continue;
+        };
+        let file = Path::new(&caps[1]);
+        let file = if file.is_absolute() { file.to_path_buf() } else { root.join(file) };
+        let d = Diagnostic {
+            file: file.to_string_lossy().to_string(),
+            line: caps[2].parse().unwrap_or(0),
+            column: caps[3].parse().unwrap_or(0),
+            message,
+            actions: Vec::new(),
+        };
+        if !out.iter().any(|o| o.file == d.file && o.line == d.line && o.message == d.message) { out.push(d); }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(output: &str) -> Vec<(String, usize, usize, String)> {
+        errors(output, Path::new("/project")).into_iter().map(|d| (d.file, d.line, d.column, d.message)).collect()
+    }
+
+    fn at(file: &str, line: usize, column: usize, message: &str) -> (String, usize, usize, String) {
+        (file.into(), line, column, message.into())
+    }
+
+    #[test]
+    fn parses_cargo_check_errors() {
+        let human = r#"warning: unused variable: `unused`
+ --> src/main.rs:3:9
+  |
+3 |     let unused = 1;
+  |         ^^^^^^ help: if this is intentional, prefix it with an underscore: `_unused`
+  |
+  = note: `#[warn(unused_variables)]` (part of `#[warn(unused)]`) on by default
+
+error[E0308]: mismatched types
+ --> src/main.rs:2:18
+  |
+2 |     let x: u32 = "one";
+  |            ---   ^^^^^ expected `u32`, found `&str`
+  |            |
+  |            expected due to this
+
+error[E0425]: cannot find function `missing` in this scope
+ --> /elsewhere/src/lib.rs:4:5
+  |
+4 |     missing();
+  |     ^^^^^^^ not found in this scope
+
+Some errors have detailed explanations: E0308, E0425.
+For more information about an error, try `rustc --explain E0308`.
+error: could not compile `demo` (bin "demo") due to 2 previous errors
+"#;
+        assert_eq!(
+            found(human),
+            [
+                at("/project/src/main.rs", 2, 18, "error[E0308]: mismatched types"),
+                at("/elsewhere/src/lib.rs", 4, 5, "error[E0425]: cannot find function `missing` in this scope"),
+            ]
+        );
+
+        let short = "src/main.rs:3:9: warning: unused variable: `unused`
+src/main.rs:2:18: error[E0308]: mismatched types: expected `u32`, found `&str`
+src/main.rs:2:18: error[E0308]: mismatched types: expected `u32`, found `&str`
+error: could not compile `demo` (bin \"demo\") due to 1 previous error
+";
+        assert_eq!(found(short), [at("/project/src/main.rs", 2, 18, "error[E0308]: mismatched types: expected `u32`, found `&str`")]);
+    }
+
+    #[test]
+    fn parses_rustfmt_errors() {
+        // As `rustfmt` reports them, with `<stdin>` replaced by the file.
+        let output = "error: expected expression, found `;`
+ --> src/a.rs:2:13
+  |
+2 |     let x = ;
+  |             ^ expected expression
+";
+        assert_eq!(found(output), [at("/project/src/a.rs", 2, 13, "error: expected expression, found `;`")]);
+        assert!(found("warning: something\n --> src/a.rs:1:1\n").is_empty());
+    }
+}